@@ -1,36 +1,190 @@
-use std::{collections::HashMap, fs::File, io::Write, ops::Deref, panic};
+use std::{collections::HashMap, rc::Rc};
 
 use crate::{
+    error::{Error, Location},
     instructions::{self, Inst_Set, Pad},
-    parser::{self, Literal, ParseValue, Parser},
+    lexer::Token,
+    parser::{Literal, ParseValue, Parser},
+    program::{DebugInfo, Program},
 };
 
 pub struct CodeGen {
+    file: Rc<str>,
     ast: Vec<ParseValue>,
     labels:HashMap<String,i32>,
+    // `.reserve` names and `.data` labels, as memory addresses, and the image they point into
+    data_labels: HashMap<String, i32>,
+    data: Vec<u8>,
+    // zero bytes set aside by `.reserve`, placed after `data`
+    bss: usize,
 }
 
 impl CodeGen {
-    pub fn new(parser:&mut Parser) -> Self {
+    pub fn new(parser:&mut Parser) -> Result<Self, Error> {
 
-        Self {
-            ast: parser.parse().to_vec(),
-            labels:parser.labels.clone()
+        let mut codegen = Self {
+            file: parser.file.clone(),
+            ast: parser.parse()?.to_vec(),
+            labels: HashMap::new(),
+            data_labels: HashMap::new(),
+            data: vec![],
+            bss: 0,
+        };
+        codegen.bind_labels()?;
+        Ok(codegen)
+    }
+
+    // first pass: a label is the index of the next instruction emitted after it,
+    // data is laid out in memory in source order and its names point into it,
+    // with every `.reserve` after all of it
+    fn bind_labels(&mut self) -> Result<(), Error> {
+        let mut defined = HashMap::<String, &Token>::new();
+        let mut reserves = vec![];
+        let mut address = 0;
+        for i in &self.ast {
+            match i {
+                ParseValue::BYTES { bytes, .. } => self.data.extend(bytes),
+                ParseValue::LABEL { token, name }
+                | ParseValue::RESERVE { token, name, .. }
+                | ParseValue::DATA_LABEL { token, name } => {
+                    if let Some(first) = defined.get(name.as_str()) {
+                        return Err(self.error(
+                            token,
+                            format!("`{name}` is already defined on line {}", first.line),
+                        ));
+                    }
+                    defined.insert(name.to_string(), token);
+                    match i {
+                        ParseValue::LABEL { .. } => {
+                            self.labels.insert(name.to_string(), address);
+                        }
+                        ParseValue::RESERVE { size, .. } => {
+                            reserves.push((name, token, self.bss));
+                            self.bss += *size as usize;
+                        }
+                        _ => {
+                            let start = self.address(token, self.data.len())?;
+                            self.data_labels.insert(name.to_string(), start);
+                        }
+                    }
+                }
+                _ => address += i.width() as i32,
+            }
+        }
+        if let Some((_, token, _)) = reserves.last() {
+            self.address(token, self.data.len() + self.bss)?;
+        }
+        for (name, token, offset) in reserves {
+            let start = self.address(token, self.data.len() + offset)?;
+            self.data_labels.insert(name.to_string(), start);
+        }
+        Ok(())
+    }
+
+    fn address(&self, token: &Token, at: usize) -> Result<i32, Error> {
+        i32::try_from(at).map_err(|_| self.error(token, "data exceeds the address space".to_owned()))
+    }
+
+    fn resolve(&self, token: &Token, value: &Literal) -> Result<i32, Error> {
+        let Literal::STRING(name) = value else {
+            return Err(self.error(token, "expected label name".to_owned()));
+        };
+        match self.labels.get(name.as_str()) {
+            Some(value) => Ok(*value),
+            None => Err(self.error(token, format!("undefined label `{name}`"))),
         }
     }
 
-    #[allow(non_snake_case, dead_code, unused)]
-    pub fn generat_(self, file_name: &str) {
+    // `push` takes either a number or the address behind a data name
+    fn operand(&self, token: &Token, value: &Literal) -> Result<i32, Error> {
+        match value {
+            Literal::INT(value) => Ok(*value),
+            Literal::LONG(_) | Literal::FLOAT(_) => Err(self.error(token, "expected a 32-bit integer".to_owned())),
+            Literal::STRING(name) if self.labels.contains_key(name.as_str()) => Err(self.error(
+                token,
+                format!("`{name}` is a code label, `push` needs a number or a data name"),
+            )),
+            Literal::STRING(name) => match self.data_labels.get(name.as_str()) {
+                Some(address) => Ok(*address),
+                None => Err(self.error(token, format!("undefined name `{name}`"))),
+            },
+        }
+    }
+
+    fn error(&self, token: &Token, message: String) -> Error {
+        Error::Link {
+            location: Location {
+                file: self.file.clone(),
+                line: token.line,
+                column: token.column,
+            },
+            message,
+        }
+    }
+
+    pub fn labels(&self) -> &HashMap<String, i32> {
+        &self.labels
+    }
+
+    /// Source position of every instruction `lower` emits, in the same order.
+    pub fn debug_info(&self) -> DebugInfo {
+        let locations = self
+            .ast
+            .iter()
+            .filter_map(|i| Some((i.token()?, i.width())))
+            .flat_map(|(t, width)| std::iter::repeat_n((t.line as u32, t.column as u32), width))
+            .collect();
+        DebugInfo {
+            file: self.file.to_string(),
+            locations,
+        }
+    }
+
+    /// The code plus every label as a symbol, ordered by address, the data
+    /// image with its names, and the source map.
+    pub fn program(&self) -> Result<Program, Error> {
+        let mut program = Program::new(self.lower()?);
+        program.debug = Some(self.debug_info());
+        program.data = self.data.clone();
+        program.bss = self.bss as u32;
+        program.data_symbols = self
+            .data_labels
+            .iter()
+            .map(|(name, address)| (name.clone(), *address as u32))
+            .collect();
+        program.data_symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        program.symbols = self
+            .labels
+            .iter()
+            .map(|(name, address)| (name.clone(), *address as u32))
+            .collect();
+        program.symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        Ok(program)
+    }
+
+    #[allow(unused)]
+    pub fn lower(&self) -> Result<Vec<Inst_Set>, Error> {
         let mut code = Vec::<instructions::Inst_Set>::new();
-        for i in self.ast {
+        for i in &self.ast {
             match i {
+                ParseValue::LABEL { .. }
+                | ParseValue::RESERVE { .. }
+                | ParseValue::DATA_LABEL { .. }
+                | ParseValue::BYTES { .. } => {}
                 ParseValue::PUSH { token, value } => {
-                    let Literal::INT(value) = value else{
-                        panic!("expected integer")
-                    };
-
+                    let value = self.operand(token, value)?;
                     code.push(instructions::Inst_Set::INST_PUSH { value });
                 }
+                ParseValue::PUSHL { token, value } | ParseValue::PUSHF { token, value } => {
+                    let bits = match *value {
+                        Literal::LONG(value) => value as u64,
+                        Literal::FLOAT(value) => value.to_bits(),
+                        _ => return Err(self.error(token, "expected a 64-bit number".to_owned())),
+                    };
+                    // low word first, so the high word ends up on top
+                    code.push(instructions::Inst_Set::INST_PUSH { value: bits as u32 as i32 });
+                    code.push(instructions::Inst_Set::INST_PUSH { value: (bits >> 32) as u32 as i32 });
+                }
                 ParseValue::POP { token } => {
                     code.push(instructions::Inst_Set::INST_POP { _pad: Pad::Padding });
                 }
@@ -40,7 +194,7 @@ impl CodeGen {
                 ParseValue::CMPNE { token } => {
                     code.push(instructions::Inst_Set::INST_CMPNE { _pad: Pad::Padding });
                 }
-                ParseValue::DUP { token: Token } => {
+                ParseValue::DUP { token } => {
                     code.push(instructions::Inst_Set::INST_DUP { _pad: Pad::Padding });
                 }
                 ParseValue::ADD { token } => {
@@ -55,37 +209,23 @@ impl CodeGen {
                 ParseValue::MUL { token } => {
                     code.push(instructions::Inst_Set::INST_MUL { _pad: Pad::Padding });
                 }
-                ParseValue::DIV { token: Token } => {
+                ParseValue::DIV { token } => {
                     code.push(instructions::Inst_Set::INST_DIV { _pad: Pad::Padding });
                 }
                 ParseValue::PRINT { token } => {
                     code.push(instructions::Inst_Set::INST_PRINT { _pad: Pad::Padding });
                 }
                 ParseValue::ZJMP { token, value } => {
-                    let Literal::STRING(a) = value else{
-                        panic!("Expected Label name");
-                    };
-                    let Some(value) = self.labels.get(&a.clone().to_string()) else{
-                        panic!("")
-                    };
-                    code.push(instructions::Inst_Set::INST_ZJMP { value: *value });
+                    let value = self.resolve(token, value)?;
+                    code.push(instructions::Inst_Set::INST_ZJMP { value });
                 }
                 ParseValue::NZJMP { token, value } => {
-                    let Literal::STRING(a) = value else{
-                        panic!("Expected Label name");
-                    };
-                    let Some(value) = self.labels.get(&a.clone().to_string()) else{
-                        panic!("expected int");
-                    };
-                    code.push(instructions::Inst_Set::INST_NZJMP { value: *value });
+                    let value = self.resolve(token, value)?;
+                    code.push(instructions::Inst_Set::INST_NZJMP { value });
                 }
                 ParseValue::JP { token, value } => {
-                    let Literal::STRING(a) = value else{
-                        panic!("Expected Label name");
-                    };
-                    let value = a.deref();
-                    let value= self.labels.get(value).unwrap();
-                    code.push(instructions::Inst_Set::INST_JP { value: *value});                
+                    let value = self.resolve(token, value)?;
+                    code.push(instructions::Inst_Set::INST_JP { value });
                 }
                 ParseValue::CMPG { token } => {
                     code.push(instructions::Inst_Set::INST_CMPG { _pad: Pad::Padding });
@@ -109,26 +249,195 @@ impl CodeGen {
                     code.push(instructions::Inst_Set::INST_HALT { _pad: Pad::Padding });
                 }
                 ParseValue::INDUP { token, value } => {
-                    let Literal::INT(a) = value else{
-                        panic!("expected int");
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
                     };
-                    code.push(instructions::Inst_Set::INST_INDUP { value: a as i32 });
+                    code.push(instructions::Inst_Set::INST_INDUP { value: a });
                 }
                 ParseValue::ISWAP { token, value } => {
-                    let Literal::INT(a) = value else{
-                        panic!("expected int");
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
+                    };
+                    code.push(instructions::Inst_Set::INST_ISWAP { value: a });
+                }
+                ParseValue::CALL { token, value } => {
+                    let value = self.resolve(token, value)?;
+                    code.push(instructions::Inst_Set::INST_CALL { value });
+                }
+                ParseValue::RET { token } => {
+                    code.push(instructions::Inst_Set::INST_RET { _pad: Pad::Padding });
+                }
+                ParseValue::LOAD8 { token } => {
+                    code.push(instructions::Inst_Set::INST_LOAD8 { _pad: Pad::Padding });
+                }
+                ParseValue::LOAD32 { token } => {
+                    code.push(instructions::Inst_Set::INST_LOAD32 { _pad: Pad::Padding });
+                }
+                ParseValue::STORE8 { token } => {
+                    code.push(instructions::Inst_Set::INST_STORE8 { _pad: Pad::Padding });
+                }
+                ParseValue::STORE32 { token } => {
+                    code.push(instructions::Inst_Set::INST_STORE32 { _pad: Pad::Padding });
+                }
+                ParseValue::PUTC { token } => {
+                    code.push(instructions::Inst_Set::INST_PUTC { _pad: Pad::Padding });
+                }
+                ParseValue::PUTS { token } => {
+                    code.push(instructions::Inst_Set::INST_PUTS { _pad: Pad::Padding });
+                }
+                ParseValue::PUTI { token } => {
+                    code.push(instructions::Inst_Set::INST_PUTI { _pad: Pad::Padding });
+                }
+                ParseValue::GETC { token } => {
+                    code.push(instructions::Inst_Set::INST_GETC { _pad: Pad::Padding });
+                }
+                ParseValue::READINT { token } => {
+                    code.push(instructions::Inst_Set::INST_READINT { _pad: Pad::Padding });
+                }
+                ParseValue::SYSCALL { token, value } => {
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
                     };
-                    code.push(instructions::Inst_Set::INST_ISWAP { value: a as i32 });
+                    code.push(instructions::Inst_Set::INST_SYSCALL { value: a });
+                }
+                ParseValue::AND { token } => {
+                    code.push(instructions::Inst_Set::INST_AND { _pad: Pad::Padding });
+                }
+                ParseValue::OR { token } => {
+                    code.push(instructions::Inst_Set::INST_OR { _pad: Pad::Padding });
+                }
+                ParseValue::XOR { token } => {
+                    code.push(instructions::Inst_Set::INST_XOR { _pad: Pad::Padding });
+                }
+                ParseValue::NOT { token } => {
+                    code.push(instructions::Inst_Set::INST_NOT { _pad: Pad::Padding });
+                }
+                ParseValue::SHL { token } => {
+                    code.push(instructions::Inst_Set::INST_SHL { _pad: Pad::Padding });
+                }
+                ParseValue::SHR { token } => {
+                    code.push(instructions::Inst_Set::INST_SHR { _pad: Pad::Padding });
+                }
+                ParseValue::USHR { token } => {
+                    code.push(instructions::Inst_Set::INST_USHR { _pad: Pad::Padding });
+                }
+                ParseValue::EQ { token } => {
+                    code.push(instructions::Inst_Set::INST_EQ { _pad: Pad::Padding });
+                }
+                ParseValue::NE { token } => {
+                    code.push(instructions::Inst_Set::INST_NE { _pad: Pad::Padding });
+                }
+                ParseValue::LT { token } => {
+                    code.push(instructions::Inst_Set::INST_LT { _pad: Pad::Padding });
+                }
+                ParseValue::GT { token } => {
+                    code.push(instructions::Inst_Set::INST_GT { _pad: Pad::Padding });
+                }
+                ParseValue::LE { token } => {
+                    code.push(instructions::Inst_Set::INST_LE { _pad: Pad::Padding });
+                }
+                ParseValue::GE { token } => {
+                    code.push(instructions::Inst_Set::INST_GE { _pad: Pad::Padding });
+                }
+                ParseValue::ADDC { token } => {
+                    code.push(instructions::Inst_Set::INST_ADDC { _pad: Pad::Padding });
+                }
+                ParseValue::SUBC { token } => {
+                    code.push(instructions::Inst_Set::INST_SUBC { _pad: Pad::Padding });
+                }
+                ParseValue::MULC { token } => {
+                    code.push(instructions::Inst_Set::INST_MULC { _pad: Pad::Padding });
+                }
+                ParseValue::LADD { token } => {
+                    code.push(instructions::Inst_Set::INST_LADD { _pad: Pad::Padding });
+                }
+                ParseValue::LSUB { token } => {
+                    code.push(instructions::Inst_Set::INST_LSUB { _pad: Pad::Padding });
+                }
+                ParseValue::LMUL { token } => {
+                    code.push(instructions::Inst_Set::INST_LMUL { _pad: Pad::Padding });
+                }
+                ParseValue::LDIV { token } => {
+                    code.push(instructions::Inst_Set::INST_LDIV { _pad: Pad::Padding });
+                }
+                ParseValue::LMOD { token } => {
+                    code.push(instructions::Inst_Set::INST_LMOD { _pad: Pad::Padding });
+                }
+                ParseValue::LPRINT { token } => {
+                    code.push(instructions::Inst_Set::INST_LPRINT { _pad: Pad::Padding });
+                }
+                ParseValue::I2L { token } => {
+                    code.push(instructions::Inst_Set::INST_I2L { _pad: Pad::Padding });
+                }
+                ParseValue::L2I { token } => {
+                    code.push(instructions::Inst_Set::INST_L2I { _pad: Pad::Padding });
+                }
+                ParseValue::LCMP { token } => {
+                    code.push(instructions::Inst_Set::INST_LCMP { _pad: Pad::Padding });
+                }
+                ParseValue::FADD { token } => {
+                    code.push(instructions::Inst_Set::INST_FADD { _pad: Pad::Padding });
+                }
+                ParseValue::FSUB { token } => {
+                    code.push(instructions::Inst_Set::INST_FSUB { _pad: Pad::Padding });
+                }
+                ParseValue::FMUL { token } => {
+                    code.push(instructions::Inst_Set::INST_FMUL { _pad: Pad::Padding });
+                }
+                ParseValue::FDIV { token } => {
+                    code.push(instructions::Inst_Set::INST_FDIV { _pad: Pad::Padding });
+                }
+                ParseValue::FPRINT { token } => {
+                    code.push(instructions::Inst_Set::INST_FPRINT { _pad: Pad::Padding });
+                }
+                ParseValue::I2F { token } => {
+                    code.push(instructions::Inst_Set::INST_I2F { _pad: Pad::Padding });
+                }
+                ParseValue::F2I { token } => {
+                    code.push(instructions::Inst_Set::INST_F2I { _pad: Pad::Padding });
+                }
+                ParseValue::FCMP { token } => {
+                    code.push(instructions::Inst_Set::INST_FCMP { _pad: Pad::Padding });
+                }
+                ParseValue::L2F { token } => {
+                    code.push(instructions::Inst_Set::INST_L2F { _pad: Pad::Padding });
+                }
+                ParseValue::F2L { token } => {
+                    code.push(instructions::Inst_Set::INST_F2L { _pad: Pad::Padding });
+                }
+                ParseValue::PICK { token, value } => {
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
+                    };
+                    code.push(instructions::Inst_Set::INST_PICK { value: a });
+                }
+                ParseValue::ROLL { token, value } => {
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
+                    };
+                    code.push(instructions::Inst_Set::INST_ROLL { value: a });
+                }
+                ParseValue::OVER { token } => {
+                    code.push(instructions::Inst_Set::INST_OVER { _pad: Pad::Padding });
+                }
+                ParseValue::ROT { token } => {
+                    code.push(instructions::Inst_Set::INST_ROT { _pad: Pad::Padding });
+                }
+                ParseValue::GETABS { token, value } => {
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
+                    };
+                    code.push(instructions::Inst_Set::INST_GETABS { value: a });
+                }
+                ParseValue::SETABS { token, value } => {
+                    let Literal::INT(a) = *value else{
+                        return Err(self.error(token, "expected integer".to_owned()));
+                    };
+                    code.push(instructions::Inst_Set::INST_SETABS { value: a });
                 }
                 ParseValue::EOF => {}
             }
         }
-        Self::write_to_file(file_name, code.as_mut_slice());
-    }
-
-    fn write_to_file(path: &str, program: &mut [Inst_Set]) {
-        let mut file = File::create(path).unwrap();
-        let bytes: &[u8] = bytemuck::must_cast_slice(program);
-        file.write_all(bytes).unwrap();
+        Ok(code)
     }
 }
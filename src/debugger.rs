@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use colored::*;
+
+use crate::{
+    assemble_named,
+    error::Error,
+    instructions::Inst_Set,
+    program::{DebugInfo, Program},
+    virtual_m::{Trap, Vm, VmBuilder},
+};
+
+const HELP: &str = "\
+commands:
+  s, step [n]        execute n instructions (default 1)
+  c, continue        run until a breakpoint, a trap or the end
+  b, break <at>      set a breakpoint on an instruction index or label
+  d, delete <at>     remove a breakpoint
+  bl                 list breakpoints
+  st, stack          print the stack, bottom first
+  m, mem <at> [n]    dump n bytes of memory from an address or data name (default 16)
+  i, info            print ip, sp and the current instruction
+  l, list [n]        show n instructions around ip (default 5)
+  q, quit            leave the debugger
+an empty line repeats the last command";
+
+// the `.tim` file a binary was built from, used to show source lines
+struct Source {
+    file: String,
+    text: Vec<String>,
+    lines: Vec<u32>,
+    labels: Vec<(String, u32)>,
+}
+
+impl Source {
+    fn find(path: &str, program: &Program) -> Option<Self> {
+        match &program.debug {
+            Some(debug) => Self::from_debug(path, debug),
+            None => Self::reassemble(path, &program.code),
+        }
+    }
+
+    /// Reads the file named in the debug section, or a file of that name next
+    /// to the binary when it was built somewhere else.
+    fn from_debug(path: &str, debug: &DebugInfo) -> Option<Self> {
+        let named = Path::new(&debug.file);
+        let beside = Path::new(path).with_file_name(named.file_name()?);
+        let (file, data) = [named, beside.as_path()]
+            .into_iter()
+            .find_map(|file| Some((file.to_str()?, fs::read_to_string(file).ok()?)))?;
+        Some(Self {
+            file: file.to_owned(),
+            text: data.lines().map(str::to_owned).collect(),
+            lines: debug.locations.iter().map(|(line, _)| *line).collect(),
+            labels: vec![],
+        })
+    }
+
+    /// For binaries without debug info: looks for the `.tim` next to `path` and
+    /// keeps it only if it still assembles to exactly `code`, so a stale source
+    /// is never shown.
+    fn reassemble(path: &str, code: &[Inst_Set]) -> Option<Self> {
+        let file = Path::new(path).with_extension("tim");
+        let file = file.to_str()?;
+        let data = fs::read_to_string(file).ok()?;
+
+        let program = assemble_named(file, &data).ok()?;
+        if program.code != code {
+            return None;
+        }
+        let debug = program.debug.unwrap_or_default();
+        Some(Self {
+            file: file.to_owned(),
+            text: data.lines().map(str::to_owned).collect(),
+            lines: debug.locations.iter().map(|(line, _)| *line).collect(),
+            labels: program.symbols,
+        })
+    }
+
+    fn line_of(&self, ip: usize) -> Option<(usize, &str)> {
+        let line = *self.lines.get(ip)? as usize;
+        Some((line, self.text.get(line.checked_sub(1)?)?.trim()))
+    }
+}
+
+pub struct Debugger {
+    program: Program,
+    vm: Vm,
+    breakpoints: BTreeSet<usize>,
+    labels: HashMap<String, usize>,
+    source: Option<Source>,
+    trap: Option<Trap>,
+}
+
+impl Debugger {
+    /// `vm` sets the limits the program runs under, as for `run`.
+    pub fn new(path: &str, program: &Program, vm: VmBuilder) -> Result<Self, Error> {
+        let mut vm = vm.build();
+        vm.load(program)?;
+        let source = Source::find(path, program);
+
+        // symbols from the binary, topped up with labels from the source for stripped files
+        let mut labels: HashMap<String, usize> = program
+            .symbols
+            .iter()
+            .map(|(name, address)| (name.clone(), *address as usize))
+            .collect();
+        for (name, address) in source.iter().flat_map(|s| &s.labels) {
+            labels.entry(name.clone()).or_insert(*address as usize);
+        }
+        Ok(Self {
+            program: program.clone(),
+            vm,
+            breakpoints: BTreeSet::new(),
+            labels,
+            source,
+            trap: None,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        match &self.source {
+            Some(source) => println!("source: {}", source.file),
+            None => println!("{}", "no matching .tim source, showing instructions only".yellow()),
+        }
+        println!("type `help` for commands");
+        self.show_current();
+
+        let stdin = io::stdin();
+        let mut last = String::new();
+        loop {
+            print!("{}", "(dbg) ".bold());
+            io::stdout().flush().map_err(|e| Error::io("<stdout>", e))?;
+
+            let mut line = String::new();
+            let read = stdin
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| Error::io("<stdin>", e))?;
+            if read == 0 {
+                return Ok(());
+            }
+            let line = if line.trim().is_empty() { last.clone() } else { line.trim().to_owned() };
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+            match command {
+                "s" | "step" => match arg.map(str::parse::<usize>) {
+                    None => self.step(1),
+                    Some(Ok(n)) => self.step(n),
+                    Some(Err(_)) => println!("step count must be a number"),
+                },
+                "c" | "continue" => self.resume(),
+                "b" | "break" => match arg.map(|a| self.address(a)) {
+                    Some(Ok(ip)) => {
+                        self.breakpoints.insert(ip);
+                        println!("breakpoint at {ip}");
+                    }
+                    Some(Err(message)) => println!("{message}"),
+                    None => println!("usage: break <index|label>"),
+                },
+                "d" | "delete" => match arg.map(|a| self.address(a)) {
+                    Some(Ok(ip)) if self.breakpoints.remove(&ip) => println!("removed breakpoint at {ip}"),
+                    Some(Ok(ip)) => println!("no breakpoint at {ip}"),
+                    Some(Err(message)) => println!("{message}"),
+                    None => println!("usage: delete <index|label>"),
+                },
+                "bl" => {
+                    for ip in &self.breakpoints {
+                        println!("{ip}: {}", self.vm.instructions()[*ip]);
+                    }
+                }
+                "st" | "stack" => self.show_stack(),
+                "m" | "mem" => match arg {
+                    Some(at) => self.show_memory(at, words.next().and_then(|n| n.parse().ok()).unwrap_or(16)),
+                    None => println!("usage: mem <address|name> [n]"),
+                },
+                "i" | "info" => self.show_current(),
+                "l" | "list" => self.list(arg.and_then(|a| a.parse().ok()).unwrap_or(5)),
+                "q" | "quit" => return Ok(()),
+                "h" | "help" => println!("{HELP}"),
+                other => println!("unknown command `{other}`, type `help`"),
+            }
+            last = line;
+        }
+    }
+
+    // an instruction index or a label name
+    fn address(&self, at: &str) -> Result<usize, String> {
+        let ip = match at.parse::<usize>() {
+            Ok(ip) => ip,
+            Err(_) => match self.labels.get(at) {
+                Some(ip) => *ip,
+                None => return Err(format!("unknown label `{at}`")),
+            },
+        };
+        if ip >= self.vm.instructions().len() {
+            return Err(format!("{ip} is past the last instruction"));
+        }
+        Ok(ip)
+    }
+
+    // false once the program can no longer advance
+    fn single_step(&mut self) -> bool {
+        if let Some(trap) = self.trap {
+            self.report(trap);
+            return false;
+        }
+        if self.vm.is_halted() {
+            println!("program finished");
+            return false;
+        }
+        if let Err(trap) = self.vm.step() {
+            self.report(trap);
+            self.trap = Some(trap);
+            return false;
+        }
+        true
+    }
+
+    fn report(&self, trap: Trap) {
+        let source = self.source.as_ref().map(|s| s.text.join("\n"));
+        println!("{}", self.program.trap_error(trap).render(source.as_deref()));
+    }
+
+    fn step(&mut self, n: usize) {
+        for _ in 0..n {
+            if !self.single_step() {
+                return;
+            }
+        }
+        self.show_current();
+    }
+
+    fn resume(&mut self) {
+        // leave the breakpoint we are sitting on before looking for the next one
+        if !self.single_step() {
+            return;
+        }
+        while !self.vm.is_halted() && !self.breakpoints.contains(&self.vm.ip()) {
+            if !self.single_step() {
+                return;
+            }
+        }
+        if self.vm.is_halted() {
+            println!("program finished");
+            return;
+        }
+        println!("breakpoint at {}", self.vm.ip());
+        self.show_current();
+    }
+
+    fn describe(&self, ip: usize) -> String {
+        let marker = if ip == self.vm.ip() { "=>" } else { "  " };
+        let brk = if self.breakpoints.contains(&ip) { "*" } else { " " };
+        let mut out = format!("{marker}{brk}{ip:>4}: {:<12}", self.vm.instructions()[ip].to_string());
+        if let Some((line, text)) = self.source.as_ref().and_then(|s| s.line_of(ip)) {
+            let file = &self.source.as_ref().unwrap().file;
+            out.push_str(&format!(" {}", format!("{file}:{line} | {text}").dimmed()));
+        }
+        out
+    }
+
+    fn show_current(&self) {
+        if self.vm.is_halted() {
+            println!("ip={} sp={} (finished)", self.vm.ip(), self.vm.sp());
+            return;
+        }
+        match self.vm.fuel() {
+            Some(fuel) => println!("ip={} sp={} fuel={fuel}", self.vm.ip(), self.vm.sp()),
+            None => println!("ip={} sp={}", self.vm.ip(), self.vm.sp()),
+        }
+        if !self.vm.return_stack().is_empty() {
+            println!("returns to {:?}", self.vm.return_stack());
+        }
+        println!("{}", self.describe(self.vm.ip()));
+    }
+
+    fn show_stack(&self) {
+        if self.vm.stack().is_empty() {
+            println!("<empty>");
+        }
+        for (i, value) in self.vm.stack().iter().enumerate() {
+            println!("[{i}] {value}");
+        }
+    }
+
+    fn show_memory(&self, at: &str, n: usize) {
+        let start = match at.parse::<usize>() {
+            Ok(address) => address,
+            Err(_) => match self.program.data_symbols.iter().find(|(name, _)| name == at) {
+                Some((_, address)) => *address as usize,
+                None => return println!("unknown name `{at}`"),
+            },
+        };
+        let memory = self.vm.memory();
+        if start >= memory.len() {
+            return println!("{start} is past the end of memory ({} bytes)", memory.len());
+        }
+        let end = start.saturating_add(n).min(memory.len());
+        for (row, bytes) in memory[start..end].chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            println!("{:>6}: {}", start + row * 16, hex.join(" "));
+        }
+    }
+
+    fn list(&self, n: usize) {
+        let len = self.vm.instructions().len();
+        let start = self.vm.ip().saturating_sub(n / 2);
+        for ip in start..(start + n).min(len) {
+            println!("{}", self.describe(ip));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{instructions::Inst_Set, program::Program};
+
+fn jump_target(inst: &Inst_Set) -> Option<i32> {
+    match inst {
+        Inst_Set::INST_ZJMP { value }
+        | Inst_Set::INST_NZJMP { value }
+        | Inst_Set::INST_JP { value }
+        | Inst_Set::INST_CALL { value } => Some(*value),
+        _ => None,
+    }
+}
+
+/// Turns decoded instructions back into assembly the lexer accepts.
+///
+/// Labels come from the symbol section; every jump target without one gets a
+/// synthesized label (`L0`, `L1`, ... in address order), so the output
+/// reassembles to the same code and symbols. Data comes first in a `.data`
+/// section, as `.string` or `.byte` per named region, then a `.reserve` for
+/// every named region of the bss.
+pub fn disassemble(program: &Program) -> String {
+    // code and data labels share one namespace in the assembler
+    let mut taken: BTreeSet<String> = program
+        .symbols
+        .iter()
+        .chain(&program.data_symbols)
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut out = data(program, &mut taken);
+    let code = &program.code;
+    let mut labels = BTreeMap::<usize, Vec<String>>::new();
+    for (name, address) in &program.symbols {
+        labels.entry(*address as usize).or_default().push(name.clone());
+    }
+    let mut n = 0;
+    for target in code.iter().filter_map(jump_target) {
+        if let Ok(target) = usize::try_from(target)
+            && target <= code.len()
+            && !labels.contains_key(&target)
+        {
+            labels.insert(target, vec![]);
+        }
+    }
+    for names in labels.values_mut().filter(|names| names.is_empty()) {
+        names.push(fresh("L", &mut n, &mut taken));
+    }
+
+    for (ip, inst) in code.iter().enumerate() {
+        for name in labels.get(&ip).into_iter().flatten() {
+            writeln!(out, "{name}:").unwrap();
+        }
+        let target = jump_target(inst).and_then(|t| usize::try_from(t).ok());
+        match target.and_then(|t| labels.get(&t)).and_then(|names| names.first()) {
+            Some(name) => {
+                let text = inst.to_string();
+                let mnemonic = text.split_whitespace().next().unwrap_or_default();
+                writeln!(out, "{mnemonic} {name}").unwrap();
+            }
+            None => writeln!(out, "{inst}").unwrap(),
+        }
+    }
+    for name in labels.get(&code.len()).into_iter().flatten() {
+        writeln!(out, "{name}:").unwrap();
+    }
+    out
+}
+
+// splits the data and bss at every data symbol; unnamed spans get `D0`, `D1`...
+fn data(program: &Program, taken: &mut BTreeSet<String>) -> String {
+    let data = &program.data;
+    let end = data.len() + program.bss as usize;
+    let mut regions = BTreeMap::<usize, Vec<String>>::new();
+    for (name, address) in &program.data_symbols {
+        regions.entry(*address as usize).or_default().push(name.clone());
+    }
+    if end == 0 && regions.is_empty() {
+        return String::new();
+    }
+    if !data.is_empty() {
+        regions.entry(0).or_default();
+    }
+    if program.bss != 0 {
+        regions.entry(data.len()).or_default();
+    }
+    let mut n = 0;
+    for names in regions.values_mut().filter(|names| names.is_empty()) {
+        names.push(fresh("D", &mut n, taken));
+    }
+
+    let mut out = String::from(".data\n");
+    let mut starts = regions.iter().peekable();
+    while let Some((&start, names)) = starts.next() {
+        let end = starts.peek().map_or(end, |(next, _)| **next);
+        let (last, rest) = names.split_last().unwrap();
+        // a label in `.data` names the end of the initialized bytes, not a bss address
+        if start >= data.len() {
+            for name in rest {
+                writeln!(out, ".reserve {name} 0").unwrap();
+            }
+            writeln!(out, ".reserve {last} {}", end - start).unwrap();
+            continue;
+        }
+        for name in rest {
+            writeln!(out, "{name}:").unwrap();
+        }
+        let bytes = &data[start..end];
+        writeln!(out, "{last}:").unwrap();
+        match strings(bytes).filter(|_| bytes.iter().any(|b| *b != 0)) {
+            Some(strings) => {
+                for text in strings {
+                    writeln!(out, ".string \"{text}\"").unwrap();
+                }
+            }
+            None => {
+                for row in bytes.chunks(16) {
+                    let row: Vec<String> = row.iter().map(u8::to_string).collect();
+                    writeln!(out, ".byte {}", row.join(" ")).unwrap();
+                }
+            }
+        }
+    }
+    out.push_str(".text\n");
+    out
+}
+
+// the first of `{prefix}{n}`, `{prefix}{n + 1}`... not in `taken`, which it joins
+fn fresh(prefix: &str, n: &mut usize, taken: &mut BTreeSet<String>) -> String {
+    loop {
+        let name = format!("{prefix}{n}");
+        *n += 1;
+        if taken.insert(name.clone()) {
+            return name;
+        }
+    }
+}
+
+// `bytes` as `.string` literals, if it is nothing but NUL-terminated printable text
+fn strings(bytes: &[u8]) -> Option<Vec<String>> {
+    let (0, text) = bytes.split_last()? else {
+        return None;
+    };
+    let mut strings = vec![];
+    for part in text.split(|b| *b == 0) {
+        let part = std::str::from_utf8(part).ok()?;
+        let mut escaped = String::new();
+        for a in part.chars() {
+            match a {
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                '\\' => escaped.push_str("\\\\"),
+                '"' => escaped.push_str("\\\""),
+                a if a.is_control() => return None,
+                a => escaped.push(a),
+            }
+        }
+        strings.push(escaped);
+    }
+    Some(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    type Symbols = Vec<(String, u32)>;
+
+    // disassembles `program` and assembles it again: the code, data and every symbol
+    // come back, and the names the disassembler made up are returned
+    fn round_trip(program: &Program) -> (Symbols, Symbols) {
+        let text = disassemble(program);
+        let again = assemble(&text).unwrap_or_else(|error| panic!("{error:?} from\n{text}"));
+        assert_eq!(again.code, program.code, "{text}");
+        assert_eq!(again.data, program.data, "{text}");
+        assert_eq!(again.bss, program.bss, "{text}");
+        let added = |before: &[(String, u32)], after: Symbols| {
+            let (kept, added): (Vec<_>, Vec<_>) = after.into_iter().partition(|symbol| before.contains(symbol));
+            assert_eq!(kept.len(), before.len(), "{text}");
+            added
+        };
+        (added(&program.symbols, again.symbols), added(&program.data_symbols, again.data_symbols))
+    }
+
+    #[test]
+    fn named_programs_keep_their_names() {
+        let program = assemble(
+            ".data\nmsg: .string \"a\\tb\"\n.string \"c\"\nbytes: .byte 1 2 3\n.reserve buf 8\n.reserve end 0\n.text\n\
+             start: push msg puts push 2 call f halt\nf: dup zjump done pop done: ret",
+        )
+        .unwrap();
+        assert_eq!(round_trip(&program), (vec![], vec![]));
+    }
+
+    #[test]
+    fn jump_targets_without_symbols() {
+        let mut program = assemble("push 3 top: push 1 sub dup nzjump top call end halt end: ret").unwrap();
+        program.symbols.clear();
+        let (code, data) = round_trip(&program);
+        assert_eq!(code, [("L0".to_owned(), 1), ("L1".to_owned(), 7)]);
+        assert_eq!(data, []);
+    }
+
+    #[test]
+    fn data_at_unnamed_offsets() {
+        let mut program = assemble(".data\n.byte 1\nx: .byte 2 3\n.reserve buf 4\n.text\npush x load8 print").unwrap();
+        program.data_symbols.clear();
+        let (code, data) = round_trip(&program);
+        assert_eq!(code, []);
+        assert_eq!(data, [("D0".to_owned(), 0), ("D1".to_owned(), 3)]);
+    }
+
+    #[test]
+    fn made_up_names_avoid_both_tables() {
+        // a code label where an unnamed data offset would be called `D0`
+        let program = assemble(".data\n.byte 1\nx: .byte 2\n.text\nD0: push x load8 print").unwrap();
+        assert_eq!(round_trip(&program), (vec![], vec![("D1".to_owned(), 0)]));
+
+        // a data label where an unnamed jump target would be called `L0`
+        let mut program = assemble(".data\nL0: .byte 7\n.text\npush 1 zjump a push L0 load8 print a: halt").unwrap();
+        program.symbols.clear();
+        assert_eq!(round_trip(&program), (vec![("L1".to_owned(), 5)], vec![]));
+    }
+}
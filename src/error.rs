@@ -0,0 +1,177 @@
+use std::{fmt, io, rc::Rc};
+
+use colored::Colorize;
+
+use crate::virtual_m::Trap;
+
+/// A position inside an assembly source file, 1-based like every editor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Every way the toolchain can fail, from reading a file to running a program.
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, source: io::Error },
+    Lex { location: Location, message: String },
+    Parse { location: Location, message: String },
+    Link { location: Location, message: String },
+    Decode { path: String, offset: usize, message: String },
+    Verify {
+        ip: usize,
+        message: String,
+        location: Option<Location>,
+    },
+    /// The program does not fit the VM it is loaded into.
+    Load { message: String },
+    Runtime {
+        trap: Trap,
+        location: Option<Location>,
+    },
+}
+
+impl Error {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::Lex { location, .. }
+            | Error::Parse { location, .. }
+            | Error::Link { location, .. } => Some(location),
+            Error::Runtime { location, .. } | Error::Verify { location, .. } => location.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io { .. } => "io",
+            Error::Lex { .. } => "lex",
+            Error::Parse { .. } => "parse",
+            Error::Link { .. } => "link",
+            Error::Decode { .. } => "decode",
+            Error::Verify { .. } => "verify",
+            Error::Load { .. } => "load",
+            Error::Runtime { .. } => "runtime",
+        }
+    }
+
+    /// Process exit status: 1 when the program being built or loaded is wrong,
+    /// 2 when the VM stopped on a fault, 3 when a file could not be read or decoded.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Lex { .. }
+            | Error::Parse { .. }
+            | Error::Link { .. }
+            | Error::Verify { .. }
+            | Error::Load { .. } => 1,
+            Error::Runtime { .. } => 2,
+            Error::Io { .. } | Error::Decode { .. } => 3,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Error::Io { path, source } => format!("{path}: {source}"),
+            Error::Lex { message, .. }
+            | Error::Parse { message, .. }
+            | Error::Link { message, .. }
+            | Error::Load { message } => message.clone(),
+            Error::Decode {
+                path,
+                offset,
+                message,
+            } => format!("{path}: {message} at byte {offset}"),
+            Error::Runtime { trap, .. } => trap.to_string(),
+            Error::Verify { ip, message, .. } => format!("{message} at ip={ip}"),
+        }
+    }
+
+    /// Renders the error the way a compiler would, quoting the offending
+    /// line of `source` with a caret under the column when a location is known.
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut out = format!(
+            "{}: {}",
+            format!("error[{}]", self.kind()).red().bold(),
+            self.message().bold()
+        );
+        let Some(location) = self.location() else {
+            return out;
+        };
+        let gutter = " ".repeat(location.line.to_string().len());
+        out.push_str(&format!("\n{gutter}{} {location}", "-->".blue().bold()));
+
+        let text = location.line.checked_sub(1).and_then(|n| source?.lines().nth(n));
+        let Some(text) = text else {
+            return out;
+        };
+        let bar = "|".blue().bold();
+        let caret = " ".repeat(location.column.saturating_sub(1));
+        out.push_str(&format!("\n{gutter} {bar}"));
+        out.push_str(&format!(
+            "\n{} {bar} {text}",
+            location.line.to_string().blue().bold()
+        ));
+        out.push_str(&format!("\n{gutter} {bar} {caret}{}", "^".red().bold()));
+        out
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location() {
+            Some(location) => write!(f, "{location}: {} error: {}", self.kind(), self.message()),
+            None => write!(f, "{} error: {}", self.kind(), self.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Self {
+        Error::Runtime {
+            trap,
+            location: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_tolerates_line_zero() {
+        let error = Error::Parse {
+            location: Location {
+                file: "a.tim".into(),
+                line: 0,
+                column: 0,
+            },
+            message: "bad".to_owned(),
+        };
+        assert!(error.render(Some("push 1\n")).contains("a.tim:0:0"));
+    }
+}
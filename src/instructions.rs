@@ -1,12 +1,14 @@
+use std::fmt;
+
 use bytemuck::NoUninit;
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, NoUninit)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, NoUninit)]
 pub enum Pad {
     Padding,
 }
 
-#[derive(Debug, Clone, Copy, NoUninit)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, NoUninit)]
 #[repr(C)]
 #[allow(private_interfaces)]
 pub enum Inst_Set {
@@ -33,16 +35,147 @@ pub enum Inst_Set {
     INST_HALT { _pad: Pad },
     INST_INDUP { value: i32 },
     INST_ISWAP { value: i32 },
+    INST_CALL { value: i32 },
+    INST_RET { _pad: Pad },
+    INST_LOAD8 { _pad: Pad },
+    INST_LOAD32 { _pad: Pad },
+    INST_STORE8 { _pad: Pad },
+    INST_STORE32 { _pad: Pad },
+    INST_PUTC { _pad: Pad },
+    INST_PUTS { _pad: Pad },
+    INST_PUTI { _pad: Pad },
+    INST_GETC { _pad: Pad },
+    INST_READINT { _pad: Pad },
+    INST_SYSCALL { value: i32 },
+    INST_AND { _pad: Pad },
+    INST_OR { _pad: Pad },
+    INST_XOR { _pad: Pad },
+    INST_NOT { _pad: Pad },
+    INST_SHL { _pad: Pad },
+    INST_SHR { _pad: Pad },
+    INST_USHR { _pad: Pad },
+    INST_EQ { _pad: Pad },
+    INST_NE { _pad: Pad },
+    INST_LT { _pad: Pad },
+    INST_GT { _pad: Pad },
+    INST_LE { _pad: Pad },
+    INST_GE { _pad: Pad },
+    INST_ADDC { _pad: Pad },
+    INST_SUBC { _pad: Pad },
+    INST_MULC { _pad: Pad },
+    INST_LADD { _pad: Pad },
+    INST_LSUB { _pad: Pad },
+    INST_LMUL { _pad: Pad },
+    INST_LDIV { _pad: Pad },
+    INST_LMOD { _pad: Pad },
+    INST_LPRINT { _pad: Pad },
+    INST_I2L { _pad: Pad },
+    INST_L2I { _pad: Pad },
+    INST_LCMP { _pad: Pad },
+    INST_FADD { _pad: Pad },
+    INST_FSUB { _pad: Pad },
+    INST_FMUL { _pad: Pad },
+    INST_FDIV { _pad: Pad },
+    INST_FPRINT { _pad: Pad },
+    INST_I2F { _pad: Pad },
+    INST_F2I { _pad: Pad },
+    INST_FCMP { _pad: Pad },
+    INST_L2F { _pad: Pad },
+    INST_F2L { _pad: Pad },
+    INST_PICK { value: i32 },
+    INST_ROLL { value: i32 },
+    INST_OVER { _pad: Pad },
+    INST_ROT { _pad: Pad },
+    INST_GETABS { value: i32 },
+    INST_SETABS { value: i32 },
+}
+impl Inst_Set {
+    /// The 8-byte instruction word: opcode in the low 32 bits, operand in the high 32 bits.
+    pub fn encode(&self) -> u64 {
+        let [opcode, operand]: [u32; 2] = bytemuck::must_cast(*self);
+        opcode as u64 | (operand as u64) << 32
+    }
+
+    /// `(pops, pushes)` for instructions whose effect on the stack is fixed.
+    /// Stack addressing (`indup`, `pick`, ...), `call` and `syscall` depend on
+    /// their operand and return `None`.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        use Inst_Set::*;
+        let effect = match self {
+            INST_INDUP { .. }
+            | INST_ISWAP { .. }
+            | INST_GETABS { .. }
+            | INST_SETABS { .. }
+            | INST_PICK { .. }
+            | INST_ROLL { .. }
+            | INST_CALL { .. }
+            | INST_SYSCALL { .. } => return None,
+            INST_NOP { .. } | INST_HALT { .. } | INST_JP { .. } | INST_RET { .. } => (0, 0),
+            INST_PUSH { .. } | INST_GETC { .. } => (0, 1),
+            INST_READINT { .. } => (0, 2),
+            INST_POP { .. }
+            | INST_PRINT { .. }
+            | INST_PUTC { .. }
+            | INST_PUTS { .. }
+            | INST_PUTI { .. }
+            | INST_ZJMP { .. }
+            | INST_NZJMP { .. } => (1, 0),
+            INST_NOT { .. } | INST_LOAD8 { .. } | INST_LOAD32 { .. } => (1, 1),
+            INST_DUP { .. } | INST_I2L { .. } | INST_I2F { .. } => (1, 2),
+            INST_STORE8 { .. } | INST_STORE32 { .. } | INST_LPRINT { .. } | INST_FPRINT { .. } => (2, 0),
+            INST_ADD { .. }
+            | INST_SUB { .. }
+            | INST_MUL { .. }
+            | INST_DIV { .. }
+            | INST_MOD { .. }
+            | INST_ADDC { .. }
+            | INST_SUBC { .. }
+            | INST_MULC { .. }
+            | INST_AND { .. }
+            | INST_OR { .. }
+            | INST_XOR { .. }
+            | INST_SHL { .. }
+            | INST_SHR { .. }
+            | INST_USHR { .. }
+            | INST_EQ { .. }
+            | INST_NE { .. }
+            | INST_LT { .. }
+            | INST_GT { .. }
+            | INST_LE { .. }
+            | INST_GE { .. }
+            | INST_L2I { .. }
+            | INST_F2I { .. } => (2, 1),
+            INST_SWAP { .. } | INST_L2F { .. } | INST_F2L { .. } => (2, 2),
+            INST_OVER { .. } => (2, 3),
+            INST_ROT { .. } => (3, 3),
+            INST_CMPE { .. }
+            | INST_CMPNE { .. }
+            | INST_CMPG { .. }
+            | INST_CMPL { .. }
+            | INST_CMPGE { .. }
+            | INST_CMPLE { .. } => (2, 3),
+            INST_LCMP { .. } | INST_FCMP { .. } => (4, 1),
+            INST_LADD { .. }
+            | INST_LSUB { .. }
+            | INST_LMUL { .. }
+            | INST_LDIV { .. }
+            | INST_LMOD { .. }
+            | INST_FADD { .. }
+            | INST_FSUB { .. }
+            | INST_FMUL { .. }
+            | INST_FDIV { .. } => (4, 2),
+        };
+        Some(effect)
+    }
 }
+
 impl TryFrom<u64> for Inst_Set {
     type Error = String;
     fn try_from(value: u64) -> Result<Self, Self::Error> {
-        let val = (value & 0xFFFF) as u32;
-        let value = (value >> 32) as i32 ;
+        let val = value as u32;
+        let value = (value >> 32) as i32;
         match val {
-            0 => Ok(Inst_Set::INST_PUSH {
-                value: value as i32,
-            }),
+            0 => Ok(Inst_Set::INST_PUSH { value }),
             1 => Ok(Inst_Set::INST_POP { _pad: Pad::Padding }),
             2 => Ok(Inst_Set::INST_CMPE { _pad: Pad::Padding }),
             3 => Ok(Inst_Set::INST_CMPNE { _pad: Pad::Padding }),
@@ -65,7 +198,144 @@ impl TryFrom<u64> for Inst_Set {
             20 => Ok(Inst_Set::INST_HALT { _pad: Pad::Padding }),
             21 => Ok(Inst_Set::INST_INDUP { value }),
             22 => Ok(Inst_Set::INST_ISWAP { value }),
-            _ => Err("uknown instruction ".to_owned()),
+            23 => Ok(Inst_Set::INST_CALL { value }),
+            24 => Ok(Inst_Set::INST_RET { _pad: Pad::Padding }),
+            25 => Ok(Inst_Set::INST_LOAD8 { _pad: Pad::Padding }),
+            26 => Ok(Inst_Set::INST_LOAD32 { _pad: Pad::Padding }),
+            27 => Ok(Inst_Set::INST_STORE8 { _pad: Pad::Padding }),
+            28 => Ok(Inst_Set::INST_STORE32 { _pad: Pad::Padding }),
+            29 => Ok(Inst_Set::INST_PUTC { _pad: Pad::Padding }),
+            30 => Ok(Inst_Set::INST_PUTS { _pad: Pad::Padding }),
+            31 => Ok(Inst_Set::INST_PUTI { _pad: Pad::Padding }),
+            32 => Ok(Inst_Set::INST_GETC { _pad: Pad::Padding }),
+            33 => Ok(Inst_Set::INST_READINT { _pad: Pad::Padding }),
+            34 => Ok(Inst_Set::INST_SYSCALL { value }),
+            35 => Ok(Inst_Set::INST_AND { _pad: Pad::Padding }),
+            36 => Ok(Inst_Set::INST_OR { _pad: Pad::Padding }),
+            37 => Ok(Inst_Set::INST_XOR { _pad: Pad::Padding }),
+            38 => Ok(Inst_Set::INST_NOT { _pad: Pad::Padding }),
+            39 => Ok(Inst_Set::INST_SHL { _pad: Pad::Padding }),
+            40 => Ok(Inst_Set::INST_SHR { _pad: Pad::Padding }),
+            41 => Ok(Inst_Set::INST_USHR { _pad: Pad::Padding }),
+            42 => Ok(Inst_Set::INST_EQ { _pad: Pad::Padding }),
+            43 => Ok(Inst_Set::INST_NE { _pad: Pad::Padding }),
+            44 => Ok(Inst_Set::INST_LT { _pad: Pad::Padding }),
+            45 => Ok(Inst_Set::INST_GT { _pad: Pad::Padding }),
+            46 => Ok(Inst_Set::INST_LE { _pad: Pad::Padding }),
+            47 => Ok(Inst_Set::INST_GE { _pad: Pad::Padding }),
+            48 => Ok(Inst_Set::INST_ADDC { _pad: Pad::Padding }),
+            49 => Ok(Inst_Set::INST_SUBC { _pad: Pad::Padding }),
+            50 => Ok(Inst_Set::INST_MULC { _pad: Pad::Padding }),
+            51 => Ok(Inst_Set::INST_LADD { _pad: Pad::Padding }),
+            52 => Ok(Inst_Set::INST_LSUB { _pad: Pad::Padding }),
+            53 => Ok(Inst_Set::INST_LMUL { _pad: Pad::Padding }),
+            54 => Ok(Inst_Set::INST_LDIV { _pad: Pad::Padding }),
+            55 => Ok(Inst_Set::INST_LMOD { _pad: Pad::Padding }),
+            56 => Ok(Inst_Set::INST_LPRINT { _pad: Pad::Padding }),
+            57 => Ok(Inst_Set::INST_I2L { _pad: Pad::Padding }),
+            58 => Ok(Inst_Set::INST_L2I { _pad: Pad::Padding }),
+            59 => Ok(Inst_Set::INST_LCMP { _pad: Pad::Padding }),
+            60 => Ok(Inst_Set::INST_FADD { _pad: Pad::Padding }),
+            61 => Ok(Inst_Set::INST_FSUB { _pad: Pad::Padding }),
+            62 => Ok(Inst_Set::INST_FMUL { _pad: Pad::Padding }),
+            63 => Ok(Inst_Set::INST_FDIV { _pad: Pad::Padding }),
+            64 => Ok(Inst_Set::INST_FPRINT { _pad: Pad::Padding }),
+            65 => Ok(Inst_Set::INST_I2F { _pad: Pad::Padding }),
+            66 => Ok(Inst_Set::INST_F2I { _pad: Pad::Padding }),
+            67 => Ok(Inst_Set::INST_FCMP { _pad: Pad::Padding }),
+            68 => Ok(Inst_Set::INST_L2F { _pad: Pad::Padding }),
+            69 => Ok(Inst_Set::INST_F2L { _pad: Pad::Padding }),
+            70 => Ok(Inst_Set::INST_PICK { value }),
+            71 => Ok(Inst_Set::INST_ROLL { value }),
+            72 => Ok(Inst_Set::INST_OVER { _pad: Pad::Padding }),
+            73 => Ok(Inst_Set::INST_ROT { _pad: Pad::Padding }),
+            74 => Ok(Inst_Set::INST_GETABS { value }),
+            75 => Ok(Inst_Set::INST_SETABS { value }),
+            _ => Err(format!("unknown opcode {val}")),
+        }
+    }
+}
+
+// prints the instruction in the syntax the lexer accepts
+impl fmt::Display for Inst_Set {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst_Set::INST_PUSH { value } => write!(f, "push {value}"),
+            Inst_Set::INST_POP { .. } => write!(f, "pop"),
+            Inst_Set::INST_CMPE { .. } => write!(f, "cmpe"),
+            Inst_Set::INST_CMPNE { .. } => write!(f, "cmpne"),
+            Inst_Set::INST_DUP { .. } => write!(f, "dup"),
+            Inst_Set::INST_ADD { .. } => write!(f, "add"),
+            Inst_Set::INST_SWAP { .. } => write!(f, "swap"),
+            Inst_Set::INST_SUB { .. } => write!(f, "sub"),
+            Inst_Set::INST_MUL { .. } => write!(f, "mul"),
+            Inst_Set::INST_DIV { .. } => write!(f, "div"),
+            Inst_Set::INST_PRINT { .. } => write!(f, "print"),
+            Inst_Set::INST_ZJMP { value } => write!(f, "zjump {value}"),
+            Inst_Set::INST_NZJMP { value } => write!(f, "nzjump {value}"),
+            Inst_Set::INST_JP { value } => write!(f, "jump {value}"),
+            Inst_Set::INST_CMPG { .. } => write!(f, "cmpg"),
+            Inst_Set::INST_CMPL { .. } => write!(f, "cmpl"),
+            Inst_Set::INST_MOD { .. } => write!(f, "mod"),
+            Inst_Set::INST_CMPGE { .. } => write!(f, "cmpge"),
+            Inst_Set::INST_CMPLE { .. } => write!(f, "cmple"),
+            Inst_Set::INST_NOP { .. } => write!(f, "nop"),
+            Inst_Set::INST_HALT { .. } => write!(f, "halt"),
+            Inst_Set::INST_INDUP { value } => write!(f, "indup {value}"),
+            Inst_Set::INST_ISWAP { value } => write!(f, "iswap {value}"),
+            Inst_Set::INST_CALL { value } => write!(f, "call {value}"),
+            Inst_Set::INST_RET { .. } => write!(f, "ret"),
+            Inst_Set::INST_LOAD8 { .. } => write!(f, "load8"),
+            Inst_Set::INST_LOAD32 { .. } => write!(f, "load32"),
+            Inst_Set::INST_STORE8 { .. } => write!(f, "store8"),
+            Inst_Set::INST_STORE32 { .. } => write!(f, "store32"),
+            Inst_Set::INST_PUTC { .. } => write!(f, "putc"),
+            Inst_Set::INST_PUTS { .. } => write!(f, "puts"),
+            Inst_Set::INST_PUTI { .. } => write!(f, "puti"),
+            Inst_Set::INST_GETC { .. } => write!(f, "getc"),
+            Inst_Set::INST_READINT { .. } => write!(f, "readint"),
+            Inst_Set::INST_SYSCALL { value } => write!(f, "syscall {value}"),
+            Inst_Set::INST_AND { .. } => write!(f, "and"),
+            Inst_Set::INST_OR { .. } => write!(f, "or"),
+            Inst_Set::INST_XOR { .. } => write!(f, "xor"),
+            Inst_Set::INST_NOT { .. } => write!(f, "not"),
+            Inst_Set::INST_SHL { .. } => write!(f, "shl"),
+            Inst_Set::INST_SHR { .. } => write!(f, "shr"),
+            Inst_Set::INST_USHR { .. } => write!(f, "ushr"),
+            Inst_Set::INST_EQ { .. } => write!(f, "eq"),
+            Inst_Set::INST_NE { .. } => write!(f, "ne"),
+            Inst_Set::INST_LT { .. } => write!(f, "lt"),
+            Inst_Set::INST_GT { .. } => write!(f, "gt"),
+            Inst_Set::INST_LE { .. } => write!(f, "le"),
+            Inst_Set::INST_GE { .. } => write!(f, "ge"),
+            Inst_Set::INST_ADDC { .. } => write!(f, "addc"),
+            Inst_Set::INST_SUBC { .. } => write!(f, "subc"),
+            Inst_Set::INST_MULC { .. } => write!(f, "mulc"),
+            Inst_Set::INST_LADD { .. } => write!(f, "ladd"),
+            Inst_Set::INST_LSUB { .. } => write!(f, "lsub"),
+            Inst_Set::INST_LMUL { .. } => write!(f, "lmul"),
+            Inst_Set::INST_LDIV { .. } => write!(f, "ldiv"),
+            Inst_Set::INST_LMOD { .. } => write!(f, "lmod"),
+            Inst_Set::INST_LPRINT { .. } => write!(f, "lprint"),
+            Inst_Set::INST_I2L { .. } => write!(f, "i2l"),
+            Inst_Set::INST_L2I { .. } => write!(f, "l2i"),
+            Inst_Set::INST_LCMP { .. } => write!(f, "lcmp"),
+            Inst_Set::INST_FADD { .. } => write!(f, "fadd"),
+            Inst_Set::INST_FSUB { .. } => write!(f, "fsub"),
+            Inst_Set::INST_FMUL { .. } => write!(f, "fmul"),
+            Inst_Set::INST_FDIV { .. } => write!(f, "fdiv"),
+            Inst_Set::INST_FPRINT { .. } => write!(f, "fprint"),
+            Inst_Set::INST_I2F { .. } => write!(f, "i2f"),
+            Inst_Set::INST_F2I { .. } => write!(f, "f2i"),
+            Inst_Set::INST_FCMP { .. } => write!(f, "fcmp"),
+            Inst_Set::INST_L2F { .. } => write!(f, "l2f"),
+            Inst_Set::INST_F2L { .. } => write!(f, "f2l"),
+            Inst_Set::INST_PICK { value } => write!(f, "pick {value}"),
+            Inst_Set::INST_ROLL { value } => write!(f, "roll {value}"),
+            Inst_Set::INST_OVER { .. } => write!(f, "over"),
+            Inst_Set::INST_ROT { .. } => write!(f, "rot"),
+            Inst_Set::INST_GETABS { value } => write!(f, "getabs {value}"),
+            Inst_Set::INST_SETABS { value } => write!(f, "setabs {value}"),
         }
     }
 }
@@ -1,17 +1,24 @@
-use std::{collections::HashMap, fmt::Display, iter::Peekable, rc::Rc, str::Chars};
+use std::{collections::HashMap, iter::Peekable, rc::Rc, str::Chars};
+
+use crate::error::{Error, Location};
 
 
 #[derive(Debug,Clone)]
 pub enum LEXVALUES{
-    INT(i32),
+    INT(i64),
+    FLOAT(f64),
     STRING(Rc<String>),
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     INT,
+    FLOAT,
+    STRING,
     IDENTIFIER,
     LABEL_DECL,
     INST_PUSH,
+    INST_PUSHL,
+    INST_PUSHF,
     INST_POP,
     INST_CMPE,
     INST_CMPNE,
@@ -34,6 +41,65 @@ pub enum TokenType {
     INST_HALT,
     INST_INDUP,
     INST_ISWAP,
+    INST_CALL,
+    INST_RET,
+    INST_LOAD8,
+    INST_LOAD32,
+    INST_STORE8,
+    INST_STORE32,
+    INST_PUTC,
+    INST_PUTS,
+    INST_PUTI,
+    INST_GETC,
+    INST_READINT,
+    INST_SYSCALL,
+    INST_AND,
+    INST_OR,
+    INST_XOR,
+    INST_NOT,
+    INST_SHL,
+    INST_SHR,
+    INST_USHR,
+    INST_EQ,
+    INST_NE,
+    INST_LT,
+    INST_GT,
+    INST_LE,
+    INST_GE,
+    INST_ADDC,
+    INST_SUBC,
+    INST_MULC,
+    INST_LADD,
+    INST_LSUB,
+    INST_LMUL,
+    INST_LDIV,
+    INST_LMOD,
+    INST_LPRINT,
+    INST_I2L,
+    INST_L2I,
+    INST_LCMP,
+    INST_FADD,
+    INST_FSUB,
+    INST_FMUL,
+    INST_FDIV,
+    INST_FPRINT,
+    INST_I2F,
+    INST_F2I,
+    INST_FCMP,
+    INST_L2F,
+    INST_F2L,
+    INST_PICK,
+    INST_ROLL,
+    INST_OVER,
+    INST_ROT,
+    INST_GETABS,
+    INST_SETABS,
+    DIR_RESERVE,
+    DIR_DATA,
+    DIR_TEXT,
+    DIR_BYTE,
+    DIR_WORD,
+    DIR_STRING,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +107,7 @@ pub struct Token {
     pub type_: TokenType,
     pub value: Option<LEXVALUES>,
     pub line: usize,
+    pub column: usize,
 }
 
 
@@ -49,12 +116,16 @@ pub struct Lexer<'a> {
     data: Peekable<Chars<'a>>,
     pub tokens: Vec<Token>,
     keywords: HashMap<String, TokenType>,
+    pub file: Rc<str>,
     line: usize,
+    column: usize,
 }
 impl<'a> Lexer<'a> {
-    pub fn read_source(data: &'a str) -> Self {
+    pub fn read_source(file: &str, data: &'a str) -> Self {
         let mut map = HashMap::new();
         map.insert("PUSH".to_lowercase(), TokenType::INST_PUSH);
+        map.insert("PUSHL".to_lowercase(), TokenType::INST_PUSHL);
+        map.insert("PUSHF".to_lowercase(), TokenType::INST_PUSHF);
         map.insert("POP".to_lowercase(), TokenType::INST_POP);
         map.insert("CMPE".to_lowercase(), TokenType::INST_CMPE);
         map.insert("DUP".to_lowercase(), TokenType::INST_DUP);
@@ -79,65 +150,387 @@ impl<'a> Lexer<'a> {
         map.insert("HALT".to_lowercase(), TokenType::INST_HALT);
         map.insert("INDUP".to_lowercase(), TokenType::INST_INDUP);
         map.insert("ISWAP".to_lowercase(), TokenType::INST_ISWAP);
+        map.insert("CALL".to_lowercase(), TokenType::INST_CALL);
+        map.insert("RET".to_lowercase(), TokenType::INST_RET);
+        map.insert("LOAD8".to_lowercase(), TokenType::INST_LOAD8);
+        map.insert("LOAD32".to_lowercase(), TokenType::INST_LOAD32);
+        map.insert("STORE8".to_lowercase(), TokenType::INST_STORE8);
+        map.insert("STORE32".to_lowercase(), TokenType::INST_STORE32);
+        map.insert("PUTC".to_lowercase(), TokenType::INST_PUTC);
+        map.insert("PUTS".to_lowercase(), TokenType::INST_PUTS);
+        map.insert("PUTI".to_lowercase(), TokenType::INST_PUTI);
+        map.insert("GETC".to_lowercase(), TokenType::INST_GETC);
+        map.insert("READINT".to_lowercase(), TokenType::INST_READINT);
+        map.insert("SYSCALL".to_lowercase(), TokenType::INST_SYSCALL);
+        map.insert("AND".to_lowercase(), TokenType::INST_AND);
+        map.insert("OR".to_lowercase(), TokenType::INST_OR);
+        map.insert("XOR".to_lowercase(), TokenType::INST_XOR);
+        map.insert("NOT".to_lowercase(), TokenType::INST_NOT);
+        map.insert("SHL".to_lowercase(), TokenType::INST_SHL);
+        map.insert("SHR".to_lowercase(), TokenType::INST_SHR);
+        map.insert("USHR".to_lowercase(), TokenType::INST_USHR);
+        map.insert("EQ".to_lowercase(), TokenType::INST_EQ);
+        map.insert("NE".to_lowercase(), TokenType::INST_NE);
+        map.insert("LT".to_lowercase(), TokenType::INST_LT);
+        map.insert("GT".to_lowercase(), TokenType::INST_GT);
+        map.insert("LE".to_lowercase(), TokenType::INST_LE);
+        map.insert("GE".to_lowercase(), TokenType::INST_GE);
+        map.insert("ADDC".to_lowercase(), TokenType::INST_ADDC);
+        map.insert("SUBC".to_lowercase(), TokenType::INST_SUBC);
+        map.insert("MULC".to_lowercase(), TokenType::INST_MULC);
+        map.insert("LADD".to_lowercase(), TokenType::INST_LADD);
+        map.insert("LSUB".to_lowercase(), TokenType::INST_LSUB);
+        map.insert("LMUL".to_lowercase(), TokenType::INST_LMUL);
+        map.insert("LDIV".to_lowercase(), TokenType::INST_LDIV);
+        map.insert("LMOD".to_lowercase(), TokenType::INST_LMOD);
+        map.insert("LPRINT".to_lowercase(), TokenType::INST_LPRINT);
+        map.insert("I2L".to_lowercase(), TokenType::INST_I2L);
+        map.insert("L2I".to_lowercase(), TokenType::INST_L2I);
+        map.insert("LCMP".to_lowercase(), TokenType::INST_LCMP);
+        map.insert("FADD".to_lowercase(), TokenType::INST_FADD);
+        map.insert("FSUB".to_lowercase(), TokenType::INST_FSUB);
+        map.insert("FMUL".to_lowercase(), TokenType::INST_FMUL);
+        map.insert("FDIV".to_lowercase(), TokenType::INST_FDIV);
+        map.insert("FPRINT".to_lowercase(), TokenType::INST_FPRINT);
+        map.insert("I2F".to_lowercase(), TokenType::INST_I2F);
+        map.insert("F2I".to_lowercase(), TokenType::INST_F2I);
+        map.insert("FCMP".to_lowercase(), TokenType::INST_FCMP);
+        map.insert("L2F".to_lowercase(), TokenType::INST_L2F);
+        map.insert("F2L".to_lowercase(), TokenType::INST_F2L);
+        map.insert("PICK".to_lowercase(), TokenType::INST_PICK);
+        map.insert("ROLL".to_lowercase(), TokenType::INST_ROLL);
+        map.insert("OVER".to_lowercase(), TokenType::INST_OVER);
+        map.insert("ROT".to_lowercase(), TokenType::INST_ROT);
+        map.insert("GETABS".to_lowercase(), TokenType::INST_GETABS);
+        map.insert("SETABS".to_lowercase(), TokenType::INST_SETABS);
+        map.insert(".reserve".to_owned(), TokenType::DIR_RESERVE);
+        map.insert(".data".to_owned(), TokenType::DIR_DATA);
+        map.insert(".text".to_owned(), TokenType::DIR_TEXT);
+        map.insert(".byte".to_owned(), TokenType::DIR_BYTE);
+        map.insert(".word".to_owned(), TokenType::DIR_WORD);
+        map.insert(".string".to_owned(), TokenType::DIR_STRING);
         
         Self {
             data: data.chars().peekable(),
             tokens: vec![],
             keywords: map,
-            line: 0,
+            file: file.into(),
+            line: 1,
+            column: 1,
         }
     }
-    pub fn push_token(&mut self, type_: TokenType, value: Option<LEXVALUES>, line: usize) {
-        let token = Token { type_, value, line };
+    pub fn push_token(&mut self, type_: TokenType, value: Option<LEXVALUES>, line: usize, column: usize) {
+        let token = Token { type_, value, line, column };
         self.tokens.push(token);
     }
+
+    pub fn location(&self, line: usize, column: usize) -> Location {
+        Location {
+            file: self.file.clone(),
+            line,
+            column,
+        }
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let a = self.data.next()?;
+        if a == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(a)
+    }
+
     // till the mutable reffrence is alive the result is alive
-    pub fn lexe(&'a mut self) -> Result<&'a [Token], String> {
-        while let Some(a) = self.data.next() {
-            if a == '\n'{
-                self.line +=1;
-            }
+    pub fn lexe(&'a mut self) -> Result<&'a [Token], Error> {
+        loop {
+            let (line, column) = (self.line, self.column);
+            let Some(a) = self.advance() else {
+                break;
+            };
             match a {
                 x if x.is_ascii_whitespace() => {}
-                x if x.is_ascii_digit() && !x.is_ascii_whitespace() => {
-                    let mut digit = String::new();
-                    digit.push(x);
+                ';' | '#' => {
                     while let Some(a) = self.data.peek()
-                        && a.is_ascii_digit()
+                        && *a != '\n'
                     {
-                        digit.push(self.data.next().unwrap());
+                        self.advance();
                     }
-                    //println!("=>{}", digit);
-                    let data: i32 = digit.parse().unwrap();
-                    self.push_token(TokenType::INT, Some(LEXVALUES::INT(data)), self.line);
-
                 }
-                x if x.is_ascii_alphabetic() && !x.is_ascii_whitespace() => {
+                '/' if self.data.peek() == Some(&'*') => {
+                    self.advance();
+                    self.skip_block_comment(line, column)?;
+                }
+                x if x.is_ascii_digit()
+                    || (x == '-' && self.data.peek().is_some_and(char::is_ascii_digit)) =>
+                {
+                    let data = self.number(x, line, column)?;
+                    let type_ = match data {
+                        LEXVALUES::FLOAT(_) => TokenType::FLOAT,
+                        _ => TokenType::INT,
+                    };
+                    self.push_token(type_, Some(data), line, column);
+                }
+                '.' if self.data.peek().is_some_and(char::is_ascii_alphabetic) => {
+                    let mut key = String::from(".");
+                    while let Some(a) = self.data.peek()
+                        && a.is_ascii_alphanumeric()
+                    {
+                        key.push(self.advance().unwrap());
+                    }
+                    let Some(token) = self.keywords.get(&key.to_lowercase()).copied() else {
+                        return Err(Error::Lex {
+                            location: self.location(line, column),
+                            message: format!("unknown directive `{key}`"),
+                        });
+                    };
+                    self.push_token(token, None, line, column);
+                }
+                '\'' => {
+                    let data = self.char_literal(line, column)?;
+                    self.push_token(TokenType::INT, Some(LEXVALUES::INT(data.into())), line, column);
+                }
+                '"' => {
+                    let data = self.string_literal(line, column)?;
+                    self.push_token(TokenType::STRING, Some(LEXVALUES::STRING(Rc::new(data))), line, column);
+                }
+                x if x.is_ascii_alphabetic() => {
                     let mut key = String::new();
                     key.push(x);
                     while let Some(a) = self.data.peek() &&  Self::is_valid(*a) {
-                        let d = self.data.next().unwrap();
+                        let d = self.advance().unwrap();
                         key.push(d);
                     }
 
                     let token = self.keywords.get(&key.to_lowercase());
                     if let Some(a) = token {
-                        self.push_token(*a, None, self.line);
+                        self.push_token(*a, None, line, column);
                     } else if key.contains(":"){
                         let key = key.replace(":", "");
-                        self.push_token(TokenType::LABEL_DECL, Some(LEXVALUES::STRING(Rc::new(key))), self.line);
+                        self.push_token(TokenType::LABEL_DECL, Some(LEXVALUES::STRING(Rc::new(key))), line, column);
                     }else {
-                        self.push_token(TokenType::IDENTIFIER, Some(LEXVALUES::STRING(Rc::new(key))), self.line);
+                        self.push_token(TokenType::IDENTIFIER, Some(LEXVALUES::STRING(Rc::new(key))), line, column);
                     }
                 }
-                _ => return Err(format!("uknown symbol {a}")),
+                _ => {
+                    return Err(Error::Lex {
+                        location: self.location(line, column),
+                        message: format!("unknown symbol `{a}`"),
+                    });
+                }
             }
         }
         Ok(self.tokens.as_slice())
     }
 
 
+    // decimal, or 0x/0b/0o prefixed, with an optional leading `-` and `_` separators
+    // integers are kept as i64 here; each operand checks the width it needs
+    fn number(&mut self, first: char, line: usize, column: usize) -> Result<LEXVALUES, Error> {
+        let mut literal = String::new();
+        literal.push(first);
+        while let Some(a) = self.data.peek() {
+            let prefixed = ["0x", "0X", "0b", "0B", "0o", "0O"]
+                .iter()
+                .any(|p| literal.trim_start_matches('-').starts_with(p));
+            let exponent_sign = (*a == '+' || *a == '-') && literal.ends_with(['e', 'E']);
+            if !(a.is_ascii_alphanumeric() || *a == '_' || (!prefixed && (*a == '.' || exponent_sign))) {
+                break;
+            }
+            literal.push(self.advance().unwrap());
+        }
+        let error = |message: String| Error::Lex {
+            location: self.location(line, column),
+            message,
+        };
+
+        let (negative, body) = match literal.strip_prefix('-') {
+            Some(body) => (true, body),
+            None => (false, literal.as_str()),
+        };
+        let (radix, digits) = match body.get(..2) {
+            Some("0x" | "0X") => (16, &body[2..]),
+            Some("0b" | "0B") => (2, &body[2..]),
+            Some("0o" | "0O") => (8, &body[2..]),
+            _ => (10, body),
+        };
+        let digits = digits.replace('_', "");
+        if radix == 10 && digits.contains(['.', 'e', 'E']) {
+            return match literal.replace('_', "").parse::<f64>() {
+                Ok(value) if value.is_finite() => Ok(LEXVALUES::FLOAT(value)),
+                Ok(_) => Err(error(format!("float literal `{literal}` is out of range"))),
+                Err(_) => Err(error(format!("invalid float literal `{literal}`"))),
+            };
+        }
+        if digits.is_empty() {
+            return Err(error(format!("integer literal `{literal}` has no digits")));
+        }
+        if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix)) {
+            return Err(error(format!("invalid digit `{bad}` in base {radix} literal `{literal}`")));
+        }
+
+        let value = u64::from_str_radix(&digits, radix)
+            .ok()
+            .map(i128::from)
+            .map(|v| if negative { -v } else { v })
+            .and_then(|v| i64::try_from(v).ok());
+        value.map(LEXVALUES::INT).ok_or_else(|| {
+            error(format!(
+                "integer literal `{literal}` does not fit in i64 ({} to {})",
+                i64::MIN,
+                i64::MAX
+            ))
+        })
+    }
+
+    // 'A', or one of the escapes \n \t \r \0 \\ \'
+    fn char_literal(&mut self, line: usize, column: usize) -> Result<i32, Error> {
+        let error = |lexer: &Self, message: &str| Error::Lex {
+            location: lexer.location(line, column),
+            message: message.to_owned(),
+        };
+        let value = match self.advance() {
+            Some('\\') => self
+                .escape()
+                .ok_or_else(|| error(self, "unknown escape in character literal"))?,
+            Some('\'') | Some('\n') | None => {
+                return Err(error(self, "empty character literal"));
+            }
+            Some(a) => a,
+        };
+        if self.advance() != Some('\'') {
+            return Err(error(self, "unterminated character literal"));
+        }
+        Ok(value as i32)
+    }
+
+    // the opening quote is already consumed; a string may not span lines
+    fn string_literal(&mut self, line: usize, column: usize) -> Result<String, Error> {
+        let error = |lexer: &Self, message: &str| Error::Lex {
+            location: lexer.location(line, column),
+            message: message.to_owned(),
+        };
+        let mut value = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.escape() {
+                    Some(a) => value.push(a),
+                    None => return Err(error(self, "unknown escape in string literal")),
+                },
+                Some('\n') | None => return Err(error(self, "unterminated string literal")),
+                Some(a) => value.push(a),
+            }
+        }
+    }
+
+    // the character after a backslash, shared by character and string literals
+    fn escape(&mut self) -> Option<char> {
+        match self.advance()? {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '0' => Some('\0'),
+            '\\' => Some('\\'),
+            '\'' => Some('\''),
+            '"' => Some('"'),
+            _ => None,
+        }
+    }
+
+    // newlines inside the comment still go through `advance`, so later tokens keep their line
+    fn skip_block_comment(&mut self, line: usize, column: usize) -> Result<(), Error> {
+        while let Some(a) = self.advance() {
+            if a == '*' && self.data.peek() == Some(&'/') {
+                self.advance();
+                return Ok(());
+            }
+        }
+        Err(Error::Lex {
+            location: self.location(line, column),
+            message: "unterminated block comment".to_owned(),
+        })
+    }
+
     fn is_valid(a:char)->bool{
-       a == ':' ||  a.is_ascii_alphabetic()
+       a == ':' || a == '_' || a.is_ascii_alphanumeric()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Result<Vec<Token>, Error> {
+        let mut lexer = Lexer::read_source("<test>", source);
+        lexer.lexe().map(<[Token]>::to_vec)
+    }
+
+    fn int(source: &str) -> i64 {
+        match &lex(source).unwrap()[0].value {
+            Some(LEXVALUES::INT(value)) => *value,
+            other => panic!("`{source}` lexed to {other:?}"),
+        }
+    }
+
+    fn float(source: &str) -> f64 {
+        match &lex(source).unwrap()[0].value {
+            Some(LEXVALUES::FLOAT(value)) => *value,
+            other => panic!("`{source}` lexed to {other:?}"),
+        }
+    }
+
+    fn error(source: &str) -> String {
+        match lex(source) {
+            Err(Error::Lex { message, .. }) => message,
+            other => panic!("`{source}` lexed to {other:?}"),
+        }
+    }
+
+    #[test]
+    fn prefixes_and_separators() {
+        assert_eq!(int("42"), 42);
+        assert_eq!(int("-1"), -1);
+        assert_eq!(int("0xff"), 255);
+        assert_eq!(int("0XFF"), 255);
+        assert_eq!(int("-0x10"), -16);
+        assert_eq!(int("0b1010"), 10);
+        assert_eq!(int("0o17"), 15);
+        assert_eq!(int("1_000_000"), 1_000_000);
+        assert_eq!(int("0xdead_beef"), 0xdead_beef);
+        assert_eq!(int("'A'"), 65);
+        assert_eq!(int("'\\n'"), 10);
+    }
+
+    #[test]
+    fn i64_limits() {
+        assert_eq!(int("-9223372036854775808"), i64::MIN);
+        assert_eq!(int("9223372036854775807"), i64::MAX);
+        assert!(error("9223372036854775808").contains("does not fit in i64"));
+        assert!(error("-9223372036854775809").contains("does not fit in i64"));
+        assert!(error("0x1_0000_0000_0000_0000").contains("does not fit in i64"));
+    }
+
+    #[test]
+    fn malformed_integers() {
+        assert_eq!(error("0x"), "integer literal `0x` has no digits");
+        assert_eq!(error("0b102"), "invalid digit `2` in base 2 literal `0b102`");
+        assert_eq!(error("12ab"), "invalid digit `a` in base 10 literal `12ab`");
+    }
+
+    #[test]
+    fn floats_and_hex_e() {
+        assert_eq!(float("1.5"), 1.5);
+        assert_eq!(float("-0.25"), -0.25);
+        assert_eq!(float("1e3"), 1000.0);
+        assert_eq!(float("2.5E-2"), 0.025);
+        assert_eq!(float("1_000.5"), 1000.5);
+        // `e` is a hex digit, not an exponent, after `0x`
+        assert_eq!(int("0x1e"), 30);
+        assert_eq!(int("0x1e5"), 0x1e5);
+        assert!(error("1e999").contains("out of range"));
+        assert!(error("1.2.3").contains("invalid float literal"));
     }
 }
@@ -0,0 +1,43 @@
+//! Assembler and stack-based virtual machine for `.tim` programs.
+//!
+//! ```
+//! use virtual_tsoding::{assemble, Vm};
+//!
+//! let program = assemble("push 2 push 3 add print").unwrap();
+//! let mut vm = Vm::builder().stack_size(64).build();
+//! vm.load(&program).unwrap();
+//! vm.start().unwrap();
+//! ```
+#![allow(non_camel_case_types, clippy::upper_case_acronyms)]
+
+pub mod codegen;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod instructions;
+pub mod lexer;
+pub mod parser;
+pub mod program;
+pub mod verify;
+pub mod virtual_m;
+
+pub use error::{Error, Location};
+pub use instructions::Inst_Set;
+pub use program::{DebugInfo, Program};
+pub use virtual_m::{ArithMode, Syscall, Trap, TrapKind, Vm, VmBuilder};
+
+use codegen::CodeGen;
+use lexer::Lexer;
+use parser::Parser;
+
+/// Assembles source text into a program. Diagnostics name the file `<input>`.
+pub fn assemble(source: &str) -> Result<Program, Error> {
+    assemble_named("<input>", source)
+}
+
+/// Like [`assemble`], with `file` used in diagnostics and the debug section.
+pub fn assemble_named(file: &str, source: &str) -> Result<Program, Error> {
+    let mut lexer = Lexer::read_source(file, source);
+    let mut parser = Parser::new(&mut lexer)?;
+    CodeGen::new(&mut parser)?.program()
+}
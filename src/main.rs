@@ -1,75 +1,395 @@
-#![allow(non_camel_case_types)]
 use colored::*;
-use crate::instructions::Inst_Set;
-use crate::instructions::Pad;
-use crate::lexer::Lexer;
-use crate::virtual_m::Vm;
 use std::env::args;
-use std::env::set_var;
-use std::fs;
-use std::{
-    fs::File,
-    io::{Read, Write},
-    vec,
-};
-mod codegen;
-mod instructions;
-mod lexer;
-mod parser;
-mod virtual_m;
-
-macro_rules! INS {
-    ($val:literal,$name:ident) => {
-        Inst_Set::$name { value: $val }
-    };
-    ($name:ident) => {
-        Inst_Set::$name { _pad: Pad::Padding }
-    };
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::process::exit;
+use virtual_tsoding::debugger::Debugger;
+use virtual_tsoding::program::MAGIC;
+use virtual_tsoding::verify::Depth;
+use virtual_tsoding::virtual_m::DEFAULT_STACK_SIZE;
+use virtual_tsoding::{assemble_named, disasm, ArithMode, Error, Program, Vm, VmBuilder};
+
+const USAGE: &str = "\
+usage: virtual_tsoding <command> [options] <file>
+
+commands:
+  build, b     assemble a .tim file into a .msm binary
+  run, r       run a .msm binary, or a .tim file assembled in memory
+  disasm       print a .msm binary as assembly
+  check        assemble and check stack depths without writing anything
+  debug, d     step through a program interactively
+
+options:
+  -o <path>        output file for build (default: input with .msm), `-` for stdout
+  --strip-debug    leave the source map out of the binary
+  --depths         print each source line with the stack depth before it (check)
+  --syscall <n:pops:pushes>
+                   declare the stack effect of a host syscall (check; repeatable)
+  --input <path>   file the program reads with getc/readint (run, debug; default
+                   stdin for run, nothing for debug, whose commands come from stdin)
+  --stack-size <n> values the stack holds (run, debug, check; default 1024)
+  --call-depth <n> nested calls before a return stack overflow (run, debug; default 256)
+  --memory <n>     bytes of memory (run, debug; default 65536)
+  --fuel <n>       stop with a trap after n instructions (run, debug; default unlimited)
+  --arith <mode>   overflow of add/sub/mul/div: wrapping, checked or saturating
+                   (run, debug; default wrapping)
+  -h, --help       print this help
+  -V, --version    print the version
+
+<file> may be `-` to read from stdin.
+
+exit status: 0 success, 1 invalid program, 2 runtime trap,
+             3 unreadable or undecodable file, 64 bad command line";
+
+const EXIT_USAGE: i32 = 64;
+
+struct Options {
+    command: String,
+    input: String,
+    output: Option<String>,
+    strip_debug: bool,
+    depths: bool,
+    // `(number, pops, pushes)` of the syscalls an embedder will register
+    syscalls: Vec<(i32, usize, usize)>,
+    // what `getc`/`readint` read, instead of stdin
+    program_input: Option<String>,
+    vm_options: VmOptions,
+}
+
+// VM settings from the command line, `None` keeping the library default
+#[derive(Default)]
+struct VmOptions {
+    stack_size: Option<usize>,
+    call_depth: Option<usize>,
+    memory: Option<usize>,
+    fuel: Option<u64>,
+    arith: Option<ArithMode>,
+}
+
+impl VmOptions {
+    fn is_set(&self) -> bool {
+        self.stack_size.is_some() || self.call_depth.is_some() || self.memory.is_some() || self.fuel.is_some() || self.arith.is_some()
+    }
+
+    fn vm(&self) -> VmBuilder {
+        let mut vm = Vm::builder();
+        if let Some(size) = self.stack_size {
+            vm = vm.stack_size(size);
+        }
+        if let Some(depth) = self.call_depth {
+            vm = vm.call_depth(depth);
+        }
+        if let Some(size) = self.memory {
+            vm = vm.memory_size(size);
+        }
+        if let Some(fuel) = self.fuel {
+            vm = vm.fuel(fuel);
+        }
+        if let Some(mode) = self.arith {
+            vm = vm.arith_mode(mode);
+        }
+        vm
+    }
 }
 
 fn main() {
-    let arg: Vec<String> = args().collect();
-    if arg.len() > 1 {
-        println!("{:?}", arg);
-        if &arg[1] == "r" {
-            let code = read_from_file(&arg[2]);
-
-            let mut vm = Vm::default();
-            vm.copy_ins(&code);
-            vm.start();
-        } else if &arg[1] == "b" {
-            let file_name = &arg[2];
-            if !file_name.contains(".tim") {
-                panic!("uknown file type");
+    let arg: Vec<String> = args().skip(1).collect();
+    let options = match parse_args(&arg) {
+        Ok(Some(options)) => options,
+        Ok(None) => return,
+        Err(message) => {
+            eprintln!("{}: {message}", "error".red().bold());
+            eprintln!("run with --help for usage");
+            exit(EXIT_USAGE);
+        }
+    };
+
+    let mut cli = Cli { source: None };
+    if let Err(err) = cli.run(&options) {
+        let source = err.location().and_then(|location| cli.source_of(&location.file));
+        eprintln!("{}", err.render(source.as_deref()));
+        exit(err.exit_code());
+    }
+}
+
+// Ok(None) when the request was --help or --version and nothing is left to do
+fn parse_args(arg: &[String]) -> Result<Option<Options>, String> {
+    let mut command = None;
+    let mut input = None;
+    let mut output = None;
+    let mut strip_debug = false;
+    let mut depths = false;
+    let mut syscalls = vec![];
+    let mut program_input = None;
+    let mut vm_options = VmOptions::default();
+
+    let mut arg = arg.iter();
+    while let Some(a) = arg.next() {
+        match a.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(None);
+            }
+            "-V" | "--version" => {
+                println!("virtual_tsoding {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            }
+            "-o" => match arg.next() {
+                Some(path) => output = Some(path.clone()),
+                None => return Err("`-o` needs a path".to_owned()),
+            },
+            "--strip-debug" => strip_debug = true,
+            "--depths" => depths = true,
+            "--syscall" => syscalls.push(syscall(arg.next())?),
+            "--input" => match arg.next() {
+                Some(path) => program_input = Some(path.clone()),
+                None => return Err("`--input` needs a path".to_owned()),
+            },
+            "--stack-size" => vm_options.stack_size = Some(number(a, arg.next())?),
+            "--call-depth" => vm_options.call_depth = Some(number(a, arg.next())?),
+            "--memory" => vm_options.memory = Some(number(a, arg.next())?),
+            "--fuel" => vm_options.fuel = Some(number(a, arg.next())?),
+            "--arith" => {
+                vm_options.arith = Some(match arg.next().map(String::as_str) {
+                    Some("wrapping") => ArithMode::Wrapping,
+                    Some("checked") => ArithMode::Checked,
+                    Some("saturating") => ArithMode::Saturating,
+                    _ => return Err("`--arith` needs `wrapping`, `checked` or `saturating`".to_owned()),
+                })
             }
-            let data = fs::read_to_string(file_name).unwrap();
-            
-            let data:String =data.lines()
-            .filter(|a| ! a.trim().is_empty())
-            .collect::<Vec<_>>().join("\n");
-            
-            let mut lexer = Lexer::read_source(&data);
-            let mut parser = parser::Parser::new(&mut lexer);
-            
-            let codegen = codegen::CodeGen::new(&mut parser);
-            let file_name =file_name.replace(".tim", ".msm");
-            
-            codegen.generat_(&file_name);
-            println!("{}",format!("Build Success FILE:- {file_name}.msm").green().bold());
-            println!("{}","For execution use r flag with file name".yellow());
+            flag if flag.starts_with('-') && flag != "-" => return Err(format!("unknown option `{flag}`")),
+            word if command.is_none() => command = Some(word.to_owned()),
+            word if input.is_none() => input = Some(word.to_owned()),
+            word => return Err(format!("unexpected argument `{word}`")),
         }
     }
+
+    let Some(command) = command else {
+        return Err("missing command".to_owned());
+    };
+    let command = match command.as_str() {
+        "build" | "b" => "build",
+        "run" | "r" => "run",
+        "disasm" => "disasm",
+        "check" => "check",
+        "debug" | "d" => "debug",
+        other => return Err(format!("unknown command `{other}`")),
+    };
+    let Some(input) = input else {
+        return Err(format!("`{command}` needs an input file"));
+    };
+    if command == "debug" && input == "-" {
+        return Err("`debug` reads its commands from stdin, so the program must come from a file".to_owned());
+    }
+    if output.is_some() && command != "build" {
+        return Err(format!("`-o` does not apply to `{command}`"));
+    }
+    if program_input.is_some() && command != "run" && command != "debug" {
+        return Err(format!("`--input` does not apply to `{command}`"));
+    }
+    if !syscalls.is_empty() && command != "check" {
+        return Err(format!("`--syscall` does not apply to `{command}`"));
+    }
+    if depths && command != "check" {
+        return Err(format!("`--depths` does not apply to `{command}`"));
+    }
+    // `check` verifies against the stack size, but never runs anything
+    let vm_options_apply = match command {
+        "run" | "debug" => true,
+        "check" => !VmOptions { stack_size: None, ..vm_options }.is_set(),
+        _ => false,
+    };
+    if vm_options.is_set() && !vm_options_apply {
+        return Err(match command {
+            "check" => "only `--stack-size` applies to `check`".to_owned(),
+            _ => format!("VM options do not apply to `{command}`"),
+        });
+    }
+    Ok(Some(Options {
+        command: command.to_owned(),
+        input,
+        output,
+        strip_debug,
+        depths,
+        syscalls,
+        program_input,
+        vm_options,
+    }))
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("`{flag}` needs a non-negative integer, got `{value}`")),
+        None => Err(format!("`{flag}` needs a number")),
+    }
+}
+
+// `n:pops:pushes`
+fn syscall(value: Option<&String>) -> Result<(i32, usize, usize), String> {
+    let error = || "`--syscall` needs `number:pops:pushes`, like `1:2:1`".to_owned();
+    let value = value.ok_or_else(error)?;
+    let parts: Vec<&str> = value.split(':').collect();
+    let [number, pops, pushes] = parts[..] else {
+        return Err(error());
+    };
+    match (number.parse(), pops.parse(), pushes.parse()) {
+        (Ok(number), Ok(pops), Ok(pushes)) => Ok((number, pops, pushes)),
+        _ => Err(error()),
+    }
+}
+
+struct Cli {
+    // the last assembly source read, kept for diagnostics since stdin cannot be read twice
+    source: Option<(String, String)>,
 }
-fn read_from_file(path: &str) -> Vec<Inst_Set> {
-    let mut file = File::open(path).unwrap();
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
-
-    let mut vec_ins = vec![];
-    for i in data.chunks_exact(8) {
-        let ins = u64::from_ne_bytes(i[..].try_into().unwrap());
-        let a = Inst_Set::try_from(ins).unwrap();
-        vec_ins.push(a);
-    }
-    vec_ins
+
+impl Cli {
+    fn run(&mut self, options: &Options) -> Result<(), Error> {
+        match options.command.as_str() {
+            "build" => {
+                let mut program = self.assemble(&options.input)?;
+                if options.strip_debug {
+                    program.debug = None;
+                }
+                let output = match &options.output {
+                    Some(path) => path.clone(),
+                    None if options.input == "-" => "out.msm".to_owned(),
+                    None => Path::new(&options.input).with_extension("msm").to_string_lossy().into_owned(),
+                };
+                if output == "-" {
+                    io::stdout()
+                        .write_all(&program.to_bytes())
+                        .map_err(|e| Error::io("<stdout>", e))?;
+                } else {
+                    program.save(&output)?;
+                    eprintln!("{}", format!("Build Success FILE:- {output}").green().bold());
+                }
+            }
+            "check" => {
+                let program = self.assemble(&options.input)?;
+                let stack_size = options.vm_options.stack_size.unwrap_or(DEFAULT_STACK_SIZE);
+                let depths = program.verify(stack_size, |number| {
+                    let declared = options.syscalls.iter().rev().find(|(n, _, _)| *n == number);
+                    declared.map(|&(_, pops, pushes)| (pops, pushes))
+                })?;
+                if options.depths {
+                    self.print_depths(&program, &depths);
+                }
+                eprintln!("{}", format!("ok: {} instructions", program.code.len()).green());
+            }
+            "run" => {
+                let program = self.load(&options.input)?;
+                let mut vm = Self::vm(options)?.build();
+                vm.load(&program)?;
+                if let Err(trap) = vm.start() {
+                    let top = &vm.stack()[vm.sp().saturating_sub(8)..];
+                    eprintln!(
+                        "{}",
+                        format!("ip={} sp={} top of stack={:?}", vm.ip(), vm.sp(), top).yellow()
+                    );
+                    return Err(program.trap_error(trap));
+                }
+            }
+            "disasm" => {
+                let program = self.load(&options.input)?;
+                print!("{}", disasm::disassemble(&program));
+            }
+            "debug" => {
+                let program = self.load(&options.input)?;
+                Debugger::new(&options.input, &program, Self::vm(options)?)?.run()?;
+            }
+            _ => unreachable!("parse_args only accepts known commands"),
+        }
+        Ok(())
+    }
+
+    // the VM options plus where the program's input comes from
+    fn vm(options: &Options) -> Result<VmBuilder, Error> {
+        let vm = options.vm_options.vm();
+        Ok(match &options.program_input {
+            Some(path) => vm.input(BufReader::new(File::open(path).map_err(|e| Error::io(path, e))?)),
+            None if options.command == "debug" => vm.input(io::empty()),
+            None => vm,
+        })
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![];
+        if path == "-" {
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| Error::io("<stdin>", e))?;
+        } else {
+            bytes = fs::read(path).map_err(|e| Error::io(path, e))?;
+        }
+        Ok(bytes)
+    }
+
+    fn display_name(path: &str) -> &str {
+        if path == "-" { "<stdin>" } else { path }
+    }
+
+    fn assemble_text(&mut self, path: &str, bytes: Vec<u8>) -> Result<Program, Error> {
+        let name = Self::display_name(path);
+        let text = String::from_utf8(bytes)
+            .map_err(|e| Error::io(name, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        let program = assemble_named(name, &text);
+        self.source = Some((name.to_owned(), text));
+        program
+    }
+
+    fn assemble(&mut self, path: &str) -> Result<Program, Error> {
+        let bytes = self.read(path)?;
+        self.assemble_text(path, bytes)
+    }
+
+    // compiled binaries are recognised by their magic number, anything else is assembled in memory
+    fn load(&mut self, path: &str) -> Result<Program, Error> {
+        let bytes = self.read(path)?;
+        if bytes.starts_with(&MAGIC) {
+            Program::from_bytes(Self::display_name(path), &bytes)
+        } else {
+            self.assemble_text(path, bytes)
+        }
+    }
+
+    // the source with the depth before the first instruction of each line in a margin;
+    // inside a subroutine the depth is relative to the caller's, as `name+n`
+    fn print_depths(&self, program: &Program, depths: &[Option<Depth>]) {
+        let (Some((_, text)), Some(debug)) = (&self.source, &program.debug) else {
+            return;
+        };
+        let mut by_line = HashMap::new();
+        for (&(line, _), depth) in debug.locations.iter().zip(depths) {
+            by_line.entry(line as usize).or_insert(*depth);
+        }
+        let margins: Vec<String> = (1..=text.lines().count())
+            .map(|n| match by_line.get(&n) {
+                None => String::new(),
+                Some(None) => "-".to_owned(),
+                Some(Some(Depth::Absolute(depth))) => depth.to_string(),
+                Some(Some(Depth::Relative { routine, depth })) => {
+                    match program.symbols.iter().find(|(_, address)| *address as usize == *routine) {
+                        Some((name, _)) => format!("{name}{depth:+}"),
+                        None => format!("@{routine}{depth:+}"),
+                    }
+                }
+            })
+            .collect();
+        let width = margins.iter().map(String::len).max().unwrap_or(0);
+        for (margin, line) in margins.iter().zip(text.lines()) {
+            println!("{margin:>width$} | {line}");
+        }
+    }
+
+    fn source_of(&self, file: &str) -> Option<String> {
+        match &self.source {
+            Some((name, text)) if name == file => Some(text.clone()),
+            _ => fs::read_to_string(file).ok(),
+        }
+    }
 }
@@ -1,19 +1,27 @@
-use std::{
-    cell::{RefCell, RefMut}, collections::HashMap, panic, rc::Rc
-};
+use std::rc::Rc;
 
-use crate::lexer::{self, Lexer, Token, TokenType, LEXVALUES};
+use crate::{
+    error::{Error, Location},
+    lexer::{self, Lexer, Token, TokenType, LEXVALUES},
+};
 
 #[derive(Debug, Clone)]
 pub enum Literal {
     INT(i32),
+    LONG(i64),
+    FLOAT(f64),
     STRING(Rc<String>),
 }
 
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum ParseValue {
+    LABEL { token: Token, name: Rc<String> },
     PUSH { token: Token, value: Literal },
+    /// `pushl` and `pushf` put a 64-bit value on the stack as two words,
+    /// so they lower to two `push` instructions.
+    PUSHL { token: Token, value: Literal },
+    PUSHF { token: Token, value: Literal },
     POP { token: Token },
     CMPE { token: Token },
     CMPNE { token: Token },
@@ -36,59 +44,287 @@ pub enum ParseValue {
     HALT { token: Token },
     INDUP { token: Token, value: Literal },
     ISWAP { token: Token, value: Literal },
+    CALL { token: Token, value: Literal },
+    RET { token: Token },
+    LOAD8 { token: Token },
+    LOAD32 { token: Token },
+    STORE8 { token: Token },
+    STORE32 { token: Token },
+    PUTC { token: Token },
+    PUTS { token: Token },
+    PUTI { token: Token },
+    GETC { token: Token },
+    READINT { token: Token },
+    SYSCALL { token: Token, value: Literal },
+    AND { token: Token },
+    OR { token: Token },
+    XOR { token: Token },
+    NOT { token: Token },
+    SHL { token: Token },
+    SHR { token: Token },
+    USHR { token: Token },
+    EQ { token: Token },
+    NE { token: Token },
+    LT { token: Token },
+    GT { token: Token },
+    LE { token: Token },
+    GE { token: Token },
+    ADDC { token: Token },
+    SUBC { token: Token },
+    MULC { token: Token },
+    LADD { token: Token },
+    LSUB { token: Token },
+    LMUL { token: Token },
+    LDIV { token: Token },
+    LMOD { token: Token },
+    LPRINT { token: Token },
+    I2L { token: Token },
+    L2I { token: Token },
+    LCMP { token: Token },
+    FADD { token: Token },
+    FSUB { token: Token },
+    FMUL { token: Token },
+    FDIV { token: Token },
+    FPRINT { token: Token },
+    I2F { token: Token },
+    F2I { token: Token },
+    FCMP { token: Token },
+    L2F { token: Token },
+    F2L { token: Token },
+    /// `.reserve name size`: `size` zero bytes of memory, with `name` as their address.
+    RESERVE { token: Token, name: Rc<String>, size: i32 },
+    /// A label inside `.data`, naming the address of the bytes after it.
+    DATA_LABEL { token: Token, name: Rc<String> },
+    /// Initialized bytes from `.byte`, `.word` or `.string`.
+    BYTES { token: Token, bytes: Vec<u8> },
+    PICK { token: Token, value: Literal },
+    ROLL { token: Token, value: Literal },
+    OVER { token: Token },
+    ROT { token: Token },
+    GETABS { token: Token, value: Literal },
+    SETABS { token: Token, value: Literal },
     EOF,
 }
 
+impl ParseValue {
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            ParseValue::LABEL { token, .. }
+            | ParseValue::RESERVE { token, .. }
+            | ParseValue::DATA_LABEL { token, .. }
+            | ParseValue::BYTES { token, .. }
+            | ParseValue::PUSH { token, .. }
+            | ParseValue::PUSHL { token, .. }
+            | ParseValue::PUSHF { token, .. }
+            | ParseValue::POP { token }
+            | ParseValue::CMPE { token }
+            | ParseValue::CMPNE { token }
+            | ParseValue::DUP { token }
+            | ParseValue::ADD { token }
+            | ParseValue::SWAP { token }
+            | ParseValue::SUB { token }
+            | ParseValue::MUL { token }
+            | ParseValue::DIV { token }
+            | ParseValue::PRINT { token }
+            | ParseValue::ZJMP { token, .. }
+            | ParseValue::NZJMP { token, .. }
+            | ParseValue::JP { token, .. }
+            | ParseValue::CMPG { token }
+            | ParseValue::CMPL { token }
+            | ParseValue::MOD { token }
+            | ParseValue::CMPGE { token }
+            | ParseValue::CMPLE { token }
+            | ParseValue::NOP { token }
+            | ParseValue::HALT { token }
+            | ParseValue::INDUP { token, .. }
+            | ParseValue::ISWAP { token, .. }
+            | ParseValue::CALL { token, .. }
+            | ParseValue::RET { token }
+            | ParseValue::LOAD8 { token }
+            | ParseValue::LOAD32 { token }
+            | ParseValue::STORE8 { token }
+            | ParseValue::STORE32 { token }
+            | ParseValue::PUTC { token }
+            | ParseValue::PUTS { token }
+            | ParseValue::PUTI { token }
+            | ParseValue::GETC { token }
+            | ParseValue::READINT { token }
+            | ParseValue::SYSCALL { token, .. }
+            | ParseValue::AND { token }
+            | ParseValue::OR { token }
+            | ParseValue::XOR { token }
+            | ParseValue::NOT { token }
+            | ParseValue::SHL { token }
+            | ParseValue::SHR { token }
+            | ParseValue::USHR { token }
+            | ParseValue::EQ { token }
+            | ParseValue::NE { token }
+            | ParseValue::LT { token }
+            | ParseValue::GT { token }
+            | ParseValue::LE { token }
+            | ParseValue::GE { token }
+            | ParseValue::ADDC { token }
+            | ParseValue::SUBC { token }
+            | ParseValue::MULC { token }
+            | ParseValue::LADD { token }
+            | ParseValue::LSUB { token }
+            | ParseValue::LMUL { token }
+            | ParseValue::LDIV { token }
+            | ParseValue::LMOD { token }
+            | ParseValue::LPRINT { token }
+            | ParseValue::I2L { token }
+            | ParseValue::L2I { token }
+            | ParseValue::LCMP { token }
+            | ParseValue::FADD { token }
+            | ParseValue::FSUB { token }
+            | ParseValue::FMUL { token }
+            | ParseValue::FDIV { token }
+            | ParseValue::FPRINT { token }
+            | ParseValue::I2F { token }
+            | ParseValue::F2I { token }
+            | ParseValue::FCMP { token }
+            | ParseValue::L2F { token }
+            | ParseValue::F2L { token }
+            | ParseValue::PICK { token, .. }
+            | ParseValue::ROLL { token, .. }
+            | ParseValue::OVER { token }
+            | ParseValue::ROT { token }
+            | ParseValue::GETABS { token, .. }
+            | ParseValue::SETABS { token, .. } => Some(token),
+            ParseValue::EOF => None,
+        }
+    }
+
+    /// Number of instructions this node lowers to.
+    pub fn width(&self) -> usize {
+        match self {
+            ParseValue::PUSHL { .. } | ParseValue::PUSHF { .. } => 2,
+            _ if self.is_instruction() => 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether this node becomes an instruction, as opposed to a label or directive.
+    pub fn is_instruction(&self) -> bool {
+        !matches!(
+            self,
+            ParseValue::LABEL { .. }
+                | ParseValue::RESERVE { .. }
+                | ParseValue::DATA_LABEL { .. }
+                | ParseValue::BYTES { .. }
+                | ParseValue::EOF
+        )
+    }
+}
+
 pub struct Parser {
+    pub file: Rc<str>,
     tokens: Vec<Token>,
     counter: usize,
     tree: Vec<ParseValue>,
-    pub labels:HashMap<String,i32>
+    // after `.data` and until `.text`
+    in_data: bool,
 }
 
 impl Parser {
-    pub fn new<'a>(lexer: &'a mut Lexer<'a>) -> Self {
-        let tokens = lexer.lexe().unwrap().to_vec();
-        
-        Self {
+    pub fn new<'a>(lexer: &'a mut Lexer<'a>) -> Result<Self, Error> {
+        let file = lexer.file.clone();
+        let tokens = lexer.lexe()?.to_vec();
+
+        Ok(Self {
+            file,
             tokens,
             counter: 0,
             tree: vec![],
-            labels: HashMap::new(),
-        }
+            in_data: false,
+        })
     }
 
-    pub fn parse(&mut self) -> &[ParseValue] {
+    pub fn parse(&mut self) -> Result<&[ParseValue], Error> {
         while self.tokens.len() > self.counter {
-            self.parse_tokens();
+            self.parse_tokens()?;
             self.counter += 1;
         }
         self.tree.push(ParseValue::EOF);
-        &self.tree
+        Ok(&self.tree)
+    }
+
+    pub fn location(&self, token: &Token) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+        }
     }
 
-    fn parse_tokens(&mut self) {
+    fn parse_tokens(&mut self) -> Result<(), Error> {
         let token = self.tokens[self.counter].clone();
+        let len = self.tree.len();
+        self.parse_token(token.clone())?;
+        if self.in_data && self.tree.len() > len && self.tree[len].is_instruction() {
+            return Err(self.error(&token, "instructions belong in the `.text` section"));
+        }
+        Ok(())
+    }
+
+    fn parse_token(&mut self, token: Token) -> Result<(), Error> {
         match token.type_ {
             lexer::TokenType::LABEL_DECL=>{
-                let tk = token.clone();
-                if let Some(LEXVALUES::STRING(a)) = token.value{
-                    self.labels.insert(a.clone().to_string(), (token.line) as i32);
-                }else {
-                    panic!("Expected label");
+                let Some(LEXVALUES::STRING(a)) = token.value.clone() else {
+                    return Err(self.error(&token, "expected label"));
+                };
+                if self.in_data {
+                    self.tree.push(ParseValue::DATA_LABEL { token, name: a });
+                } else {
+                    self.tree.push(ParseValue::LABEL { token, name: a });
                 }
-                self.tree.push(ParseValue::NOP { token:tk });
             }
 
             lexer::TokenType::INST_PUSH => {
-                let token_int = self.consume(TokenType::INT, "expected int ");
-                 let Some(LEXVALUES::INT(a)) = token_int.value else{
-                    panic!("expected integer");
+                // an integer, or the name of a reserved region standing for its address
+                let value = match self.tokens.get(self.counter + 1) {
+                    Some(Token {
+                        type_: TokenType::IDENTIFIER,
+                        value: Some(LEXVALUES::STRING(name)),
+                        ..
+                    }) => {
+                        let name = name.clone();
+                        self.counter += 1;
+                        Literal::STRING(name)
+                    }
+                    _ => {
+                        let token_int = self.consume(TokenType::INT, "expected integer or name after `push`")?;
+                        let a = self.int(&token_int)?;
+                        Literal::INT(a)
+                    }
                 };
 
-                self.tree.push(ParseValue::PUSH {
+                self.tree.push(ParseValue::PUSH { token, value });
+            }
+            TokenType::INST_PUSHL => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `pushl`")?;
+                let Some(LEXVALUES::INT(a)) = token_int.value else{
+                    return Err(self.error(&token_int, "expected integer"));
+                };
+                self.tree.push(ParseValue::PUSHL {
                     token,
-                    value: Literal::INT(a),
+                    value: Literal::LONG(a),
+                });
+            }
+            TokenType::INST_PUSHF => {
+                // a whole number is fine too: `pushf 2` pushes 2.0
+                let value = match self.tokens.get(self.counter + 1).and_then(|t| t.value.clone()) {
+                    Some(LEXVALUES::FLOAT(a)) => a,
+                    Some(LEXVALUES::INT(a)) => a as f64,
+                    _ => {
+                        let next = self.tokens.get(self.counter + 1).unwrap_or(&token);
+                        return Err(self.error(next, "expected a number after `pushf`"));
+                    }
+                };
+                self.counter += 1;
+                self.tree.push(ParseValue::PUSHF {
+                    token,
+                    value: Literal::FLOAT(value),
                 });
             }
             TokenType::INST_POP => {
@@ -122,9 +358,9 @@ impl Parser {
                 self.tree.push(ParseValue::PRINT { token });
             }
             TokenType::INST_ZJMP => {
-                let token_int = self.consume(TokenType::IDENTIFIER, "expected NAME");
+                let token_int = self.consume(TokenType::IDENTIFIER, "expected label name after `zjump`")?;
                  let Some(LEXVALUES::STRING(a)) = token_int.value else{
-                    panic!("expected integer");
+                    return Err(self.error(&token_int, "expected label name"));
                 };
     
                 self.tree.push(ParseValue::ZJMP {
@@ -133,9 +369,9 @@ impl Parser {
                 });
             }
             TokenType::INST_NZJMP => {
-              let token_int = self.consume(TokenType::IDENTIFIER, "expected NAME");
+              let token_int = self.consume(TokenType::IDENTIFIER, "expected label name after `nzjump`")?;
                  let Some(LEXVALUES::STRING(a)) = token_int.value else{
-                    panic!("expected integer");
+                    return Err(self.error(&token_int, "expected label name"));
                 };
     
                 self.tree.push(ParseValue::NZJMP {
@@ -145,9 +381,9 @@ impl Parser {
 
             }
             TokenType::INST_JP => {
-              let token_int = self.consume(TokenType::IDENTIFIER, "expected NAME");
+              let token_int = self.consume(TokenType::IDENTIFIER, "expected label name after `jump`")?;
                  let Some(LEXVALUES::STRING(a)) = token_int.value else{
-                    panic!("expected integer");
+                    return Err(self.error(&token_int, "expected label name"));
                 };
     
                 self.tree.push(ParseValue::JP {
@@ -178,10 +414,8 @@ impl Parser {
                 self.tree.push(ParseValue::HALT { token });
             }
             TokenType::INST_INDUP => {
-                let token_int = self.consume(TokenType::INT, "expected INT");
-                let Some(LEXVALUES::INT(a)) = token_int.value else{
-                    panic!("expected integer");
-                };
+                let token_int = self.consume(TokenType::INT, "expected integer after `indup`")?;
+                let a = self.int(&token_int)?;
                 self.tree.push(ParseValue::INDUP{
                     token,
                     value: Literal::INT(a),
@@ -189,24 +423,300 @@ impl Parser {
 
             }
             TokenType::INST_ISWAP => {
-                let token_int = self.consume(TokenType::INT, "expected INT");
-                let Some(LEXVALUES::INT(a)) = token_int.value else{
-                    panic!("expected integer");
-                };
+                let token_int = self.consume(TokenType::INT, "expected integer after `iswap`")?;
+                let a = self.int(&token_int)?;
                 self.tree.push(ParseValue::ISWAP {
                     token,
                     value: Literal::INT(a),
                 });
             }
-            a => panic!("uknown {:?}", token),
+            TokenType::INST_CALL => {
+                let token_name = self.consume(TokenType::IDENTIFIER, "expected label name after `call`")?;
+                let Some(LEXVALUES::STRING(a)) = token_name.value else{
+                    return Err(self.error(&token_name, "expected label name"));
+                };
+                self.tree.push(ParseValue::CALL {
+                    token,
+                    value: Literal::STRING(a),
+                });
+            }
+            TokenType::INST_RET => {
+                self.tree.push(ParseValue::RET { token });
+            }
+            TokenType::INST_LOAD8 => {
+                self.tree.push(ParseValue::LOAD8 { token });
+            }
+            TokenType::INST_LOAD32 => {
+                self.tree.push(ParseValue::LOAD32 { token });
+            }
+            TokenType::INST_STORE8 => {
+                self.tree.push(ParseValue::STORE8 { token });
+            }
+            TokenType::INST_STORE32 => {
+                self.tree.push(ParseValue::STORE32 { token });
+            }
+            TokenType::DIR_RESERVE => {
+                let token_name = self.consume(TokenType::IDENTIFIER, "expected a name after `.reserve`")?;
+                let Some(LEXVALUES::STRING(name)) = token_name.value else{
+                    return Err(self.error(&token_name, "expected a name"));
+                };
+                let token_size = self.consume(TokenType::INT, "expected a size in bytes after the name")?;
+                let size = self.int(&token_size)?;
+                if size < 0 {
+                    return Err(self.error(&token_size, "reserved size cannot be negative"));
+                }
+                self.tree.push(ParseValue::RESERVE { token, name, size });
+            }
+            TokenType::DIR_DATA => self.in_data = true,
+            TokenType::DIR_TEXT => self.in_data = false,
+            TokenType::DIR_BYTE | TokenType::DIR_WORD => {
+                let byte = token.type_ == TokenType::DIR_BYTE;
+                let directive = if byte { ".byte" } else { ".word" };
+                self.data_only(&token, directive)?;
+                let mut bytes = vec![];
+                loop {
+                    let token_int = self.consume(TokenType::INT, &format!("expected integer after `{directive}`"))?;
+                    let a = self.int(&token_int)?;
+                    if !byte {
+                        bytes.extend(a.to_le_bytes());
+                    } else if (-128..=255).contains(&a) {
+                        bytes.push(a as u8);
+                    } else {
+                        return Err(self.error(&token_int, &format!("`{a}` does not fit in a byte (-128 to 255)")));
+                    }
+                    if self.tokens.get(self.counter + 1).is_none_or(|next| next.type_ != TokenType::INT) {
+                        break;
+                    }
+                }
+                self.tree.push(ParseValue::BYTES { token, bytes });
+            }
+            TokenType::DIR_STRING => {
+                self.data_only(&token, ".string")?;
+                let token_str = self.consume(TokenType::STRING, "expected a string literal after `.string`")?;
+                let Some(LEXVALUES::STRING(a)) = token_str.value else{
+                    return Err(self.error(&token_str, "expected a string literal"));
+                };
+                let mut bytes = a.as_bytes().to_vec();
+                bytes.push(0);
+                self.tree.push(ParseValue::BYTES { token, bytes });
+            }
+            TokenType::STRING => {
+                return Err(self.error(&token, "string literals are only allowed after `.string`"));
+            }
+            TokenType::INST_PUTC => {
+                self.tree.push(ParseValue::PUTC { token });
+            }
+            TokenType::INST_PUTS => {
+                self.tree.push(ParseValue::PUTS { token });
+            }
+            TokenType::INST_PUTI => {
+                self.tree.push(ParseValue::PUTI { token });
+            }
+            TokenType::INST_GETC => {
+                self.tree.push(ParseValue::GETC { token });
+            }
+            TokenType::INST_READINT => {
+                self.tree.push(ParseValue::READINT { token });
+            }
+            TokenType::INST_SYSCALL => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `syscall`")?;
+                let a = self.int(&token_int)?;
+                self.tree.push(ParseValue::SYSCALL {
+                    token,
+                    value: Literal::INT(a),
+                });
+            }
+            TokenType::INST_AND => {
+                self.tree.push(ParseValue::AND { token });
+            }
+            TokenType::INST_OR => {
+                self.tree.push(ParseValue::OR { token });
+            }
+            TokenType::INST_XOR => {
+                self.tree.push(ParseValue::XOR { token });
+            }
+            TokenType::INST_NOT => {
+                self.tree.push(ParseValue::NOT { token });
+            }
+            TokenType::INST_SHL => {
+                self.tree.push(ParseValue::SHL { token });
+            }
+            TokenType::INST_SHR => {
+                self.tree.push(ParseValue::SHR { token });
+            }
+            TokenType::INST_USHR => {
+                self.tree.push(ParseValue::USHR { token });
+            }
+            TokenType::INST_EQ => {
+                self.tree.push(ParseValue::EQ { token });
+            }
+            TokenType::INST_NE => {
+                self.tree.push(ParseValue::NE { token });
+            }
+            TokenType::INST_LT => {
+                self.tree.push(ParseValue::LT { token });
+            }
+            TokenType::INST_GT => {
+                self.tree.push(ParseValue::GT { token });
+            }
+            TokenType::INST_LE => {
+                self.tree.push(ParseValue::LE { token });
+            }
+            TokenType::INST_GE => {
+                self.tree.push(ParseValue::GE { token });
+            }
+            TokenType::INST_ADDC => {
+                self.tree.push(ParseValue::ADDC { token });
+            }
+            TokenType::INST_SUBC => {
+                self.tree.push(ParseValue::SUBC { token });
+            }
+            TokenType::INST_MULC => {
+                self.tree.push(ParseValue::MULC { token });
+            }
+            TokenType::INST_LADD => {
+                self.tree.push(ParseValue::LADD { token });
+            }
+            TokenType::INST_LSUB => {
+                self.tree.push(ParseValue::LSUB { token });
+            }
+            TokenType::INST_LMUL => {
+                self.tree.push(ParseValue::LMUL { token });
+            }
+            TokenType::INST_LDIV => {
+                self.tree.push(ParseValue::LDIV { token });
+            }
+            TokenType::INST_LMOD => {
+                self.tree.push(ParseValue::LMOD { token });
+            }
+            TokenType::INST_LPRINT => {
+                self.tree.push(ParseValue::LPRINT { token });
+            }
+            TokenType::INST_I2L => {
+                self.tree.push(ParseValue::I2L { token });
+            }
+            TokenType::INST_L2I => {
+                self.tree.push(ParseValue::L2I { token });
+            }
+            TokenType::INST_LCMP => {
+                self.tree.push(ParseValue::LCMP { token });
+            }
+            TokenType::INST_FADD => {
+                self.tree.push(ParseValue::FADD { token });
+            }
+            TokenType::INST_FSUB => {
+                self.tree.push(ParseValue::FSUB { token });
+            }
+            TokenType::INST_FMUL => {
+                self.tree.push(ParseValue::FMUL { token });
+            }
+            TokenType::INST_FDIV => {
+                self.tree.push(ParseValue::FDIV { token });
+            }
+            TokenType::INST_FPRINT => {
+                self.tree.push(ParseValue::FPRINT { token });
+            }
+            TokenType::INST_I2F => {
+                self.tree.push(ParseValue::I2F { token });
+            }
+            TokenType::INST_F2I => {
+                self.tree.push(ParseValue::F2I { token });
+            }
+            TokenType::INST_FCMP => {
+                self.tree.push(ParseValue::FCMP { token });
+            }
+            TokenType::INST_L2F => {
+                self.tree.push(ParseValue::L2F { token });
+            }
+            TokenType::INST_F2L => {
+                self.tree.push(ParseValue::F2L { token });
+            }
+            TokenType::INST_PICK => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `pick`")?;
+                let a = self.int(&token_int)?;
+                self.tree.push(ParseValue::PICK {
+                    token,
+                    value: Literal::INT(a),
+                });
+            }
+            TokenType::INST_ROLL => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `roll`")?;
+                let a = self.int(&token_int)?;
+                self.tree.push(ParseValue::ROLL {
+                    token,
+                    value: Literal::INT(a),
+                });
+            }
+            TokenType::INST_OVER => {
+                self.tree.push(ParseValue::OVER { token });
+            }
+            TokenType::INST_ROT => {
+                self.tree.push(ParseValue::ROT { token });
+            }
+            TokenType::INST_GETABS => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `getabs`")?;
+                let a = self.int(&token_int)?;
+                self.tree.push(ParseValue::GETABS {
+                    token,
+                    value: Literal::INT(a),
+                });
+            }
+            TokenType::INST_SETABS => {
+                let token_int = self.consume(TokenType::INT, "expected integer after `setabs`")?;
+                let a = self.int(&token_int)?;
+                self.tree.push(ParseValue::SETABS {
+                    token,
+                    value: Literal::INT(a),
+                });
+            }
+            TokenType::INT | TokenType::FLOAT | TokenType::IDENTIFIER => {
+                let message = match &token.value {
+                    Some(LEXVALUES::INT(a)) => format!("expected instruction, found `{a}`"),
+                    Some(LEXVALUES::FLOAT(a)) => format!("expected instruction, found `{a}`"),
+                    Some(LEXVALUES::STRING(a)) => format!("unknown instruction `{a}`"),
+                    None => "expected instruction".to_owned(),
+                };
+                return Err(self.error(&token, &message));
+            }
         }
+        Ok(())
     }
-    pub fn consume(&mut self, expects: TokenType, error: &str) -> Token {
-        if self.tokens[self.counter + 1].type_ == expects {
-            self.counter += 1;
-            self.tokens[self.counter].clone()
+    pub fn consume(&mut self, expects: TokenType, error: &str) -> Result<Token, Error> {
+        match self.tokens.get(self.counter + 1) {
+            Some(next) if next.type_ == expects => {
+                self.counter += 1;
+                Ok(next.clone())
+            }
+            Some(next) => Err(self.error(next, error)),
+            None => Err(self.error(&self.tokens[self.counter], error)),
+        }
+    }
+
+    // an integer operand, which everywhere but `pushl` has to fit in an i32
+    fn int(&self, token: &Token) -> Result<i32, Error> {
+        let Some(LEXVALUES::INT(a)) = token.value else {
+            return Err(self.error(token, "expected integer"));
+        };
+        i32::try_from(a).map_err(|_| {
+            self.error(
+                token,
+                &format!("integer literal `{a}` does not fit in i32 ({} to {})", i32::MIN, i32::MAX),
+            )
+        })
+    }
+
+    fn data_only(&self, token: &Token, directive: &str) -> Result<(), Error> {
+        if self.in_data {
+            Ok(())
         } else {
-            panic!("{}", error);
+            Err(self.error(token, &format!("`{directive}` belongs in the `.data` section")))
+        }
+    }
+
+    fn error(&self, token: &Token, message: &str) -> Error {
+        Error::Parse {
+            location: self.location(token),
+            message: message.to_owned(),
         }
     }
 }
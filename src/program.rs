@@ -0,0 +1,453 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use crate::{
+    error::{Error, Location},
+    instructions::Inst_Set,
+    verify::{self, Depth, Rejection},
+    virtual_m::Trap,
+};
+
+/// First bytes of every compiled `.msm` file.
+pub const MAGIC: [u8; 4] = *b"\x7fMSM";
+pub const VERSION: u16 = 1;
+
+const SECTION_CODE: u32 = 1;
+const SECTION_DATA: u32 = 2;
+const SECTION_SYMBOLS: u32 = 3;
+const SECTION_DEBUG: u32 = 4;
+const SECTION_DATA_SYMBOLS: u32 = 5;
+const SECTION_BSS: u32 = 6;
+
+/// A compiled program as stored in a `.msm` file.
+///
+/// The file is a header followed by sections, every integer little-endian:
+///
+/// ```text
+/// header   magic [u8; 4], version u16, flags u16, entry u32, section count u32
+/// section  kind u32, byte length u32, payload
+/// ```
+///
+/// The code section holds one 8-byte word per instruction, opcode in the low
+/// half and operand in the high half. The data section is raw bytes, copied to
+/// memory address 0 when the program is loaded, and the bss section is the
+/// `u32` count of zero bytes reserved right after them. The optional symbol section
+/// lists labels as `address u32, length u32, name`, and the optional data
+/// symbol section names addresses in the data the same way.
+/// The optional debug section is the source file name as `length u32, name`
+/// followed by `line u32, column u32` for every instruction.
+/// Unknown section kinds are skipped so newer files still load.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub entry: u32,
+    pub code: Vec<Inst_Set>,
+    pub data: Vec<u8>,
+    /// Zero bytes set aside by `.reserve`, following `data` in memory.
+    pub bss: u32,
+    pub symbols: Vec<(String, u32)>,
+    pub data_symbols: Vec<(String, u32)>,
+    pub debug: Option<DebugInfo>,
+}
+
+/// Where each instruction came from in the assembly source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub file: String,
+    /// `(line, column)` of the instruction at the same index in the code section.
+    pub locations: Vec<(u32, u32)>,
+}
+
+impl Program {
+    pub fn new(code: Vec<Inst_Set>) -> Self {
+        Self {
+            code,
+            ..Self::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = vec![(SECTION_CODE, self.code.iter().flat_map(|i| i.encode().to_le_bytes()).collect())];
+        if !self.data.is_empty() {
+            sections.push((SECTION_DATA, self.data.clone()));
+        }
+        if self.bss != 0 {
+            sections.push((SECTION_BSS, self.bss.to_le_bytes().to_vec()));
+        }
+        if !self.symbols.is_empty() {
+            sections.push((SECTION_SYMBOLS, Self::write_symbols(&self.symbols)));
+        }
+        if !self.data_symbols.is_empty() {
+            sections.push((SECTION_DATA_SYMBOLS, Self::write_symbols(&self.data_symbols)));
+        }
+        if let Some(debug) = &self.debug {
+            let mut payload = vec![];
+            payload.extend((debug.file.len() as u32).to_le_bytes());
+            payload.extend(debug.file.as_bytes());
+            for (line, column) in &debug.locations {
+                payload.extend(line.to_le_bytes());
+                payload.extend(column.to_le_bytes());
+            }
+            sections.push((SECTION_DEBUG, payload));
+        }
+
+        let mut out = vec![];
+        out.extend(MAGIC);
+        out.extend(VERSION.to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out.extend(self.entry.to_le_bytes());
+        out.extend((sections.len() as u32).to_le_bytes());
+        for (kind, payload) in sections {
+            out.extend(kind.to_le_bytes());
+            out.extend((payload.len() as u32).to_le_bytes());
+            out.extend(payload);
+        }
+        out
+    }
+
+    fn write_symbols(symbols: &[(String, u32)]) -> Vec<u8> {
+        let mut payload = vec![];
+        for (name, address) in symbols {
+            payload.extend(address.to_le_bytes());
+            payload.extend((name.len() as u32).to_le_bytes());
+            payload.extend(name.as_bytes());
+        }
+        payload
+    }
+
+    /// Decodes a whole file, rejecting anything that is not a complete `.msm` image.
+    /// `path` is only used in error messages.
+    pub fn from_bytes(path: &str, bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader {
+            path,
+            bytes,
+            offset: 0,
+        };
+        if reader.take(4, "magic number")? != MAGIC {
+            return Err(reader.error(0, "not a compiled .msm program".to_owned()));
+        }
+        let version = reader.u16("format version")?;
+        if version != VERSION {
+            return Err(reader.error(4, format!("unsupported format version {version}, expected {VERSION}")));
+        }
+        reader.u16("flags")?;
+        let entry = reader.u32("entry point")?;
+        let count = reader.u32("section count")?;
+
+        let mut program = Program {
+            entry,
+            ..Program::default()
+        };
+        let mut has_code = false;
+        for _ in 0..count {
+            let kind = reader.u32("section kind")?;
+            let len = reader.u32("section length")? as usize;
+            let start = reader.offset;
+            let payload = reader.take(len, "section payload")?;
+            match kind {
+                SECTION_CODE => {
+                    if !len.is_multiple_of(8) {
+                        return Err(reader.error(start, format!("code section length {len} is not a multiple of 8")));
+                    }
+                    for (index, word) in payload.chunks_exact(8).enumerate() {
+                        let word = u64::from_le_bytes(word.try_into().unwrap());
+                        let inst = Inst_Set::try_from(word).map_err(|message| reader.error(start + index * 8, message))?;
+                        program.code.push(inst);
+                    }
+                    has_code = true;
+                }
+                SECTION_DATA => program.data = payload.to_vec(),
+                SECTION_BSS => {
+                    let Ok(bss) = <[u8; 4]>::try_from(payload) else {
+                        return Err(reader.error(start, format!("bss section length {len} is not 4")));
+                    };
+                    program.bss = u32::from_le_bytes(bss);
+                }
+                SECTION_SYMBOLS => {
+                    program.symbols = Self::read_symbols(Reader {
+                        path,
+                        bytes: &bytes[..start + len],
+                        offset: start,
+                    })?
+                }
+                SECTION_DATA_SYMBOLS => {
+                    program.data_symbols = Self::read_symbols(Reader {
+                        path,
+                        bytes: &bytes[..start + len],
+                        offset: start,
+                    })?
+                }
+                SECTION_DEBUG => {
+                    program.debug = Some(Self::read_debug(Reader {
+                        path,
+                        bytes: &bytes[..start + len],
+                        offset: start,
+                    })?)
+                }
+                _ => {}
+            }
+        }
+        if !has_code {
+            return Err(reader.error(reader.offset, "missing code section".to_owned()));
+        }
+        if reader.offset != bytes.len() {
+            return Err(reader.error(reader.offset, "trailing bytes after the last section".to_owned()));
+        }
+        if let Some(debug) = &program.debug
+            && debug.locations.len() != program.code.len()
+        {
+            return Err(reader.error(
+                reader.offset,
+                format!(
+                    "debug section describes {} instructions but the code section has {}",
+                    debug.locations.len(),
+                    program.code.len()
+                ),
+            ));
+        }
+        let data_end = program.data.len() + program.bss as usize;
+        if let Some((name, address)) = program.data_symbols.iter().find(|(_, a)| *a as usize > data_end) {
+            return Err(reader.error(
+                reader.offset,
+                format!("data symbol `{name}` at {address} is past the end of the data and bss sections"),
+            ));
+        }
+        if entry as usize > program.code.len() {
+            return Err(reader.error(8, format!("entry point {entry} is outside the code section")));
+        }
+        Ok(program)
+    }
+
+    // `reader` ends exactly where the symbol section does
+    fn read_symbols(mut reader: Reader) -> Result<Vec<(String, u32)>, Error> {
+        let mut symbols = vec![];
+        while reader.offset < reader.bytes.len() {
+            let address = reader.u32("symbol address")?;
+            let len = reader.u32("symbol name length")? as usize;
+            let at = reader.offset;
+            let name = reader.take(len, "symbol name")?;
+            let Ok(name) = String::from_utf8(name.to_vec()) else {
+                return Err(reader.error(at, "symbol name is not valid UTF-8".to_owned()));
+            };
+            symbols.push((name, address));
+        }
+        Ok(symbols)
+    }
+
+    fn read_debug(mut reader: Reader) -> Result<DebugInfo, Error> {
+        let len = reader.u32("debug file name length")? as usize;
+        let at = reader.offset;
+        let Ok(file) = String::from_utf8(reader.take(len, "debug file name")?.to_vec()) else {
+            return Err(reader.error(at, "debug file name is not valid UTF-8".to_owned()));
+        };
+        let mut locations = vec![];
+        while reader.offset < reader.bytes.len() {
+            let at = reader.offset;
+            let line = reader.u32("debug line")?;
+            if line == 0 {
+                return Err(reader.error(at, "debug line 0, lines start at 1".to_owned()));
+            }
+            locations.push((line, reader.u32("debug column")?));
+        }
+        Ok(DebugInfo { file, locations })
+    }
+
+    /// Source position of the instruction at `ip`, when the program carries debug info.
+    pub fn location(&self, ip: usize) -> Option<Location> {
+        let debug = self.debug.as_ref()?;
+        let (line, column) = *debug.locations.get(ip)?;
+        Some(Location {
+            file: debug.file.as_str().into(),
+            line: line as usize,
+            column: column as usize,
+        })
+    }
+
+    /// Walks every path as [`Vm::load`](crate::Vm::load) does before running the
+    /// program, with `stack_size` slots and `syscall` giving the `(pops, pushes)`
+    /// of each registered syscall. Returns the depth before every instruction.
+    pub fn verify(
+        &self,
+        stack_size: usize,
+        syscall: impl Fn(i32) -> Option<(usize, usize)>,
+    ) -> Result<Vec<Option<Depth>>, Error> {
+        verify::verify(&self.code, self.entry as usize, stack_size, syscall)
+            .map_err(|rejection| self.rejection_error(rejection))
+    }
+
+    /// Wraps a verifier rejection of this program, pointing at its source line if known.
+    fn rejection_error(&self, rejection: Rejection) -> Error {
+        Error::Verify {
+            location: self.location(rejection.ip),
+            ip: rejection.ip,
+            message: rejection.message,
+        }
+    }
+
+    /// Wraps a trap raised while running this program, pointing at its source line if known.
+    pub fn trap_error(&self, trap: Trap) -> Error {
+        Error::Runtime {
+            trap,
+            location: self.location(trap.ip),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| Error::io(path, e))?;
+        Self::from_bytes(path, &bytes)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        File::create(path)
+            .and_then(|mut file| file.write_all(&self.to_bytes()))
+            .map_err(|e| Error::io(path, e))
+    }
+}
+
+struct Reader<'a> {
+    path: &'a str,
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, offset: usize, message: String) -> Error {
+        Error::Decode {
+            path: self.path.to_owned(),
+            offset,
+            message,
+        }
+    }
+
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], Error> {
+        let end = self.offset.saturating_add(len);
+        let Some(slice) = self.bytes.get(self.offset..end) else {
+            return Err(self.error(self.offset, format!("file truncated while reading {what}")));
+        };
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, instructions::Pad};
+
+    // header is 16 bytes, then the code section's kind and length
+    const CODE: usize = 24;
+
+    fn decode_error(bytes: &[u8]) -> String {
+        match Program::from_bytes("<test>", bytes) {
+            Err(Error::Decode { message, .. }) => message,
+            other => panic!("decoded to {other:?}"),
+        }
+    }
+
+    fn sample() -> Program {
+        assemble(".data\ngreeting: .string \"hi\"\n.reserve buf 4\n.text\nstart: push greeting puts\njump start\n").unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = sample();
+        assert!(!program.data.is_empty() && !program.symbols.is_empty() && !program.data_symbols.is_empty());
+        assert_eq!(program.bss, 4);
+        assert_eq!(Program::from_bytes("<test>", &program.to_bytes()).unwrap(), program);
+
+        let mut stripped = program.clone();
+        stripped.debug = None;
+        assert_eq!(Program::from_bytes("<test>", &stripped.to_bytes()).unwrap(), stripped);
+    }
+
+    #[test]
+    fn reserve_is_not_stored() {
+        let program = assemble(".data\nmsg: .string \"hi\"\n.reserve buf 1000000\n.text\npush buf").unwrap();
+        assert_eq!(program.data, b"hi\0");
+        assert_eq!(program.bss, 1_000_000);
+        assert!(program.data_symbols.contains(&("buf".to_owned(), 3)));
+        assert!(program.to_bytes().len() < 200);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = sample().to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Program::from_bytes("<test>", &bytes[..len]).is_err(), "accepted {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        assert_eq!(decode_error(b"\x7fELF\x02\x01\x01\x00"), "not a compiled .msm program");
+        let mut bytes = sample().to_bytes();
+        bytes[4] = 9;
+        assert_eq!(decode_error(&bytes), format!("unsupported format version 9, expected {VERSION}"));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = sample().to_bytes();
+        bytes.push(0);
+        assert_eq!(decode_error(&bytes), "trailing bytes after the last section");
+    }
+
+    #[test]
+    fn rejects_bad_opcodes() {
+        let mut bytes = Program::new(vec![Inst_Set::INST_HALT { _pad: Pad::Padding }]).to_bytes();
+        bytes[CODE..CODE + 4].copy_from_slice(&999u32.to_le_bytes());
+        assert!(decode_error(&bytes).contains("999"));
+    }
+
+    #[test]
+    fn rejects_ragged_code_section() {
+        let mut bytes = Program::new(vec![]).to_bytes();
+        bytes[20..24].copy_from_slice(&3u32.to_le_bytes());
+        bytes.extend([0; 3]);
+        assert_eq!(decode_error(&bytes), "code section length 3 is not a multiple of 8");
+    }
+
+    #[test]
+    fn rejects_entry_out_of_range() {
+        let program = Program::new(vec![Inst_Set::INST_HALT { _pad: Pad::Padding }]);
+        let mut bytes = program.to_bytes();
+        bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+        assert!(Program::from_bytes("<test>", &bytes).is_ok(), "an entry one past the end halts at once");
+        bytes[8..12].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(decode_error(&bytes), "entry point 2 is outside the code section");
+    }
+
+    #[test]
+    fn rejects_debug_line_zero() {
+        let mut program = Program::new(vec![Inst_Set::INST_HALT { _pad: Pad::Padding }]);
+        program.debug = Some(DebugInfo {
+            file: "a.tim".to_owned(),
+            locations: vec![(0, 0)],
+        });
+        assert_eq!(decode_error(&program.to_bytes()), "debug line 0, lines start at 1");
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let program = sample();
+        let mut bytes = program.to_bytes();
+        let count = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        bytes[12..16].copy_from_slice(&(count + 1).to_le_bytes());
+        bytes.extend(99u32.to_le_bytes());
+        bytes.extend(2u32.to_le_bytes());
+        bytes.extend([1, 2]);
+        assert_eq!(Program::from_bytes("<test>", &bytes).unwrap(), program);
+    }
+}
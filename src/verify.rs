@@ -0,0 +1,571 @@
+//! Static checks the VM runs on a program before loading it.
+//!
+//! Every path from the entry point is walked while tracking the stack depth,
+//! so a program that could jump outside the code, pop an empty stack, grow
+//! past the stack size or reach an instruction with two different depths is
+//! refused before it runs. Subroutines are summarized by what they need from
+//! and do to their caller's stack, iterating until the summaries settle so
+//! recursive calls are covered too.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::instructions::Inst_Set;
+
+/// Why a program was refused: what is wrong with the instruction at `ip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub ip: usize,
+    pub message: String,
+}
+
+/// Stack depth before an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// Reached from the entry point: the number of values on the stack.
+    Absolute(usize),
+    /// Only reached inside the subroutine at `routine`: the depth relative to
+    /// the one its caller had at the `call`.
+    Relative { routine: usize, depth: i64 },
+}
+
+// what a subroutine does to its caller's stack, relative to the depth at the `call`;
+// `needs` and `growth` stop at one past the stack size, meaning only the VM can tell
+// (recursion that keeps values on the stack per level)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Summary {
+    needs: i64,
+    growth: i64,
+    // `None` until some `ret` is reachable
+    delta: Option<i64>,
+}
+
+struct Walk {
+    // only the instructions the walk reached
+    depths: BTreeMap<usize, i64>,
+    summary: Summary,
+    callees: Vec<usize>,
+    // a `call` the stack cannot take, only final once the summaries settle
+    bad_call: Option<Rejection>,
+}
+
+/// Checks `code` as the VM would run it from `entry` with `stack_size` slots.
+/// `syscall` gives the `(pops, pushes)` of each registered syscall.
+///
+/// Returns the depth before every reachable instruction.
+pub fn verify(
+    code: &[Inst_Set],
+    entry: usize,
+    stack_size: usize,
+    syscall: impl Fn(i32) -> Option<(usize, usize)>,
+) -> Result<Vec<Option<Depth>>, Rejection> {
+    if entry > code.len() {
+        return Err(Rejection {
+            ip: entry,
+            message: "entry point is outside the program".to_owned(),
+        });
+    }
+    let mut verifier = Verifier {
+        code,
+        stack_size: stack_size as i64,
+        syscall,
+        summaries: BTreeMap::new(),
+    };
+    // only the callers of a routine whose summary changed need walking again
+    let mut main = verifier.walk(entry, true)?;
+    let mut routines: BTreeMap<usize, Walk> = BTreeMap::new();
+    let mut callers: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    let mut worklist = Worklist::default();
+    // routines that can call themselves, worked out again once the call graph grows
+    let mut recursive: Option<BTreeSet<usize>> = None;
+    main.callees.iter().for_each(|&callee| worklist.find(callee));
+    loop {
+        while let Some(routine) = worklist.pop() {
+            let walk = verifier.walk(routine, false)?;
+            for &callee in &walk.callees {
+                if callers.entry(callee).or_default().insert(routine) {
+                    recursive = None;
+                }
+                worklist.find(callee);
+            }
+            let mut summary = walk.summary;
+            routines.insert(routine, walk);
+
+            let old = verifier.summaries.get(&routine).copied();
+            if let Some(old) = old {
+                // summaries only ever grow, or recursion could see-saw between walks
+                summary.needs = summary.needs.max(old.needs);
+                summary.growth = summary.growth.max(old.growth);
+                summary.delta = summary.delta.or(old.delta);
+            }
+            if summary == old.unwrap_or_default() {
+                verifier.summaries.insert(routine, summary);
+                continue;
+            }
+            // growing again after the first walk means recursion that keeps values on
+            // the stack per level; counting it up one level a walk would take as many
+            // walks as the stack has slots, so give up on it at once
+            if let Some(old) = old
+                && recursive.get_or_insert_with(|| cycles(&routines)).contains(&routine)
+            {
+                if summary.needs > old.needs {
+                    summary.needs = verifier.unknown();
+                }
+                if summary.growth > old.growth {
+                    summary.growth = verifier.unknown();
+                }
+            }
+            verifier.summaries.insert(routine, summary);
+            callers.get(&routine).into_iter().flatten().for_each(|&caller| worklist.push(caller));
+        }
+        // paths through calls that now return may lead to more routines
+        main = verifier.walk(entry, true)?;
+        main.callees.iter().for_each(|&callee| worklist.find(callee));
+        if worklist.pending.is_empty() {
+            break;
+        }
+    }
+    if let Some(rejection) = main.bad_call {
+        return Err(rejection);
+    }
+
+    let mut depths: Vec<Option<Depth>> = vec![None; code.len()];
+    for (ip, depth) in main.depths {
+        if let Some(slot) = depths.get_mut(ip) {
+            *slot = Some(Depth::Absolute(depth as usize));
+        }
+    }
+    for (&routine, walk) in &routines {
+        for (&ip, &depth) in &walk.depths {
+            if let Some(slot @ None) = depths.get_mut(ip) {
+                *slot = Some(Depth::Relative { routine, depth });
+            }
+        }
+    }
+    Ok(depths)
+}
+
+// routines waiting for a walk; the latest found go first, so callees tend to settle
+// before the callers that found them are walked again
+#[derive(Default)]
+struct Worklist {
+    found: Vec<usize>,
+    order: BTreeMap<usize, usize>,
+    pending: BTreeSet<usize>,
+}
+
+impl Worklist {
+    fn find(&mut self, routine: usize) {
+        if !self.order.contains_key(&routine) {
+            self.order.insert(routine, self.found.len());
+            self.found.push(routine);
+            self.push(routine);
+        }
+    }
+
+    fn push(&mut self, routine: usize) {
+        self.pending.insert(self.order[&routine]);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        self.pending.pop_last().map(|order| self.found[order])
+    }
+}
+
+// the routines on some cycle of calls, by Tarjan's strongly connected components
+fn cycles(calls: &BTreeMap<usize, Walk>) -> BTreeSet<usize> {
+    let callees = |routine: usize| calls.get(&routine).map_or(&[][..], |walk| &walk.callees[..]);
+    let mut index: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    let mut stack = vec![];
+    let mut on_stack = BTreeSet::new();
+    let mut cyclic = BTreeSet::new();
+    for &root in calls.keys() {
+        if index.contains_key(&root) {
+            continue;
+        }
+        index.insert(root, (index.len(), index.len()));
+        stack.push(root);
+        on_stack.insert(root);
+        // each frame is a routine and how many of its callees have been looked at
+        let mut frames = vec![(root, 0)];
+        while let Some((routine, next)) = frames.last_mut() {
+            let routine = *routine;
+            if let Some(&callee) = callees(routine).get(*next) {
+                *next += 1;
+                if let Some(&(callee_index, _)) = index.get(&callee) {
+                    if on_stack.contains(&callee) {
+                        let low = &mut index.get_mut(&routine).unwrap().1;
+                        *low = (*low).min(callee_index);
+                    }
+                } else {
+                    index.insert(callee, (index.len(), index.len()));
+                    stack.push(callee);
+                    on_stack.insert(callee);
+                    frames.push((callee, 0));
+                }
+                continue;
+            }
+            frames.pop();
+            let (own, low) = index[&routine];
+            if let Some(&(caller, _)) = frames.last() {
+                let caller_low = &mut index.get_mut(&caller).unwrap().1;
+                *caller_low = (*caller_low).min(low);
+            }
+            if own == low {
+                let mut component = vec![];
+                while let Some(member) = stack.pop() {
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == routine {
+                        break;
+                    }
+                }
+                if component.len() > 1 || callees(routine).contains(&routine) {
+                    cyclic.extend(component);
+                }
+            }
+        }
+    }
+    cyclic
+}
+
+struct Verifier<'a, F> {
+    code: &'a [Inst_Set],
+    stack_size: i64,
+    syscall: F,
+    summaries: BTreeMap<usize, Summary>,
+}
+
+impl<F: Fn(i32) -> Option<(usize, usize)>> Verifier<'_, F> {
+    // the cap on `needs` and `growth`: only the VM can tell
+    fn unknown(&self) -> i64 {
+        self.stack_size + 1
+    }
+
+    // every path from `start`, with depths absolute when `main` and relative to `start` otherwise
+    fn walk(&self, start: usize, main: bool) -> Result<Walk, Rejection> {
+        let len = self.code.len();
+        let unknown = self.unknown();
+        let mut walk = Walk {
+            depths: BTreeMap::new(),
+            summary: Summary::default(),
+            callees: vec![],
+            bad_call: None,
+        };
+        walk.depths.insert(start, 0);
+        let mut work = vec![start];
+        while let Some(ip) = work.pop() {
+            let depth = walk.depths[&ip];
+            if ip == len {
+                continue;
+            }
+            let inst = self.code[ip];
+            let reject = |message: String| Rejection { ip, message };
+
+            let mut next = vec![ip + 1];
+            // values that must be on the stack, the depth afterwards, and the highest
+            // depth on the way; `None` when only the VM can tell
+            let (need, after, peak) = match inst {
+                // slot `n` from the bottom and `n` down from the top both need `n + 1` values
+                Inst_Set::INST_INDUP { value } | Inst_Set::INST_GETABS { value } | Inst_Set::INST_PICK { value } => {
+                    let index = self.index(ip, value)?;
+                    (Some(index + 1), depth + 1, Some(depth + 1))
+                }
+                Inst_Set::INST_ISWAP { value } | Inst_Set::INST_ROLL { value } => {
+                    let index = self.index(ip, value)?;
+                    (Some(index + 1), depth, Some(depth))
+                }
+                Inst_Set::INST_SETABS { value } => {
+                    let index = self.index(ip, value)?;
+                    (Some(index + 2), depth - 1, Some(depth))
+                }
+                Inst_Set::INST_CALL { value } => {
+                    let target = self.target(ip, value)?;
+                    walk.callees.push(target);
+                    let callee = self.summaries.get(&target).copied().unwrap_or_default();
+                    let need = Some(callee.needs).filter(|n| *n < unknown);
+                    let peak = Some(depth + callee.growth).filter(|_| callee.growth < unknown);
+                    match callee.delta {
+                        Some(delta) => (need, depth + delta, peak),
+                        None => {
+                            // never returns, as far as we know yet
+                            next.clear();
+                            (need, depth, peak)
+                        }
+                    }
+                }
+                Inst_Set::INST_SYSCALL { value } => {
+                    let Some((pops, pushes)) = (self.syscall)(value) else {
+                        return Err(reject(format!("syscall {value} is not registered")));
+                    };
+                    let after = depth - pops as i64 + pushes as i64;
+                    (Some(pops as i64), after, Some(after.max(depth)))
+                }
+                Inst_Set::INST_RET { .. } => {
+                    if main {
+                        return Err(reject("`ret` is reachable outside any subroutine".to_owned()));
+                    }
+                    match walk.summary.delta {
+                        Some(delta) if delta != depth => {
+                            return Err(reject(format!(
+                                "returns with the stack changed by {depth:+}, but by {delta:+} on another path"
+                            )));
+                        }
+                        _ => walk.summary.delta = Some(depth),
+                    }
+                    next.clear();
+                    (Some(0), depth, Some(depth))
+                }
+                _ => {
+                    match inst {
+                        Inst_Set::INST_HALT { .. } => next.clear(),
+                        Inst_Set::INST_JP { value } => next = vec![self.target(ip, value)?],
+                        Inst_Set::INST_ZJMP { value } | Inst_Set::INST_NZJMP { value } => {
+                            next.push(self.target(ip, value)?)
+                        }
+                        _ => {}
+                    }
+                    let (pops, pushes) = inst.stack_effect().expect("only operand-dependent instructions lack an effect");
+                    let after = depth - pops as i64 + pushes as i64;
+                    (Some(pops as i64), after, Some(after.max(depth)))
+                }
+            };
+
+            // a subroutine's own depths are at least as deep in absolute terms; through
+            // a `call` they may still be heading for the recursion cap
+            let call = matches!(inst, Inst_Set::INST_CALL { .. });
+            let mut problem = None;
+            if let Some(peak) = peak
+                && peak > self.stack_size
+                && (main || !call)
+            {
+                problem = Some(format!(
+                    "`{inst}` can take the stack depth to {peak}, past its size of {}",
+                    self.stack_size
+                ));
+            }
+            if main {
+                if let Some(need) = need
+                    && depth < need
+                {
+                    problem = Some(format!("`{inst}` needs a stack depth of {need}, found {depth}"));
+                }
+            } else {
+                let needs = need.map_or(unknown, |need| need - depth);
+                walk.summary.needs = walk.summary.needs.max(needs).min(unknown);
+                walk.summary.growth = walk.summary.growth.max(peak.unwrap_or(unknown)).min(unknown);
+            }
+            match problem {
+                Some(message) if main && call => {
+                    walk.bad_call.get_or_insert(reject(message));
+                    next.clear();
+                }
+                Some(message) => return Err(reject(message)),
+                None => {}
+            }
+
+            for to in next {
+                match walk.depths.get(&to).copied() {
+                    None => {
+                        walk.depths.insert(to, after);
+                        work.push(to);
+                    }
+                    Some(known) if known != after => {
+                        return Err(Rejection {
+                            ip: to,
+                            message: format!("reached with stack depth {known} on one path and {after} on another"),
+                        });
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(walk)
+    }
+
+    // landing one past the last instruction ends the program, like in the VM
+    fn target(&self, ip: usize, value: i32) -> Result<usize, Rejection> {
+        match usize::try_from(value) {
+            Ok(target) if target <= self.code.len() => Ok(target),
+            _ => Err(Rejection {
+                ip,
+                message: format!("jump to {value} is outside the program"),
+            }),
+        }
+    }
+
+    fn index(&self, ip: usize, value: i32) -> Result<i64, Rejection> {
+        if value < 0 {
+            return Err(Rejection {
+                ip,
+                message: format!("stack index {value} is negative"),
+            });
+        }
+        Ok(value as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, instructions::Pad};
+
+    fn check(source: &str, stack_size: usize) -> Result<Vec<Option<Depth>>, Rejection> {
+        let program = assemble(source).unwrap();
+        verify(&program.code, program.entry as usize, stack_size, |_| None)
+    }
+
+    fn rejection(source: &str) -> (usize, String) {
+        let rejection = check(source, 16).unwrap_err();
+        (rejection.ip, rejection.message)
+    }
+
+    fn absolute(depths: &[Option<Depth>]) -> Vec<Option<usize>> {
+        depths
+            .iter()
+            .map(|d| match d {
+                Some(Depth::Absolute(depth)) => Some(*depth),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consistent_join() {
+        // both arms of the branch leave one value before `x`
+        let depths = check("push 0 zjump a push 1 jump x a: push 2 x: print", 16).unwrap();
+        assert_eq!(absolute(&depths), [Some(0), Some(1), Some(0), Some(1), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn inconsistent_join() {
+        assert_eq!(
+            rejection("push 1 zjump a push 2 a: halt"),
+            (3, "reached with stack depth 0 on one path and 1 on another".to_owned())
+        );
+        // a loop that leaks a value every time round
+        assert_eq!(
+            rejection("l: push 1 jump l"),
+            (0, "reached with stack depth 0 on one path and 1 on another".to_owned())
+        );
+    }
+
+    #[test]
+    fn unreachable_code_has_no_depth() {
+        let depths = check("halt pop pop", 16).unwrap();
+        assert_eq!(depths, [Some(Depth::Absolute(0)), None, None]);
+    }
+
+    #[test]
+    fn ret_in_main() {
+        assert_eq!(rejection("ret"), (0, "`ret` is reachable outside any subroutine".to_owned()));
+        // falling through into a subroutine body is the same mistake
+        assert_eq!(rejection("push 1 f: ret"), (1, "`ret` is reachable outside any subroutine".to_owned()));
+    }
+
+    #[test]
+    fn bad_targets() {
+        // the assembler only emits jumps to labels, so build the code by hand
+        let message = |code: &[Inst_Set], entry| verify(code, entry, 16, |_| None).map_err(|r| (r.ip, r.message));
+        let halt = Inst_Set::INST_HALT { _pad: Pad::Padding };
+        assert_eq!(
+            message(&[halt, Inst_Set::INST_JP { value: 5 }], 1),
+            Err((1, "jump to 5 is outside the program".to_owned()))
+        );
+        assert_eq!(
+            message(&[Inst_Set::INST_CALL { value: -1 }], 0),
+            Err((0, "jump to -1 is outside the program".to_owned()))
+        );
+        // one past the end just halts
+        assert!(message(&[Inst_Set::INST_JP { value: 1 }], 0).is_ok());
+        assert_eq!(message(&[halt], 2), Err((2, "entry point is outside the program".to_owned())));
+        assert_eq!(rejection("indup -1"), (0, "stack index -1 is negative".to_owned()));
+    }
+
+    #[test]
+    fn underflow_and_overflow() {
+        assert_eq!(rejection("push 1 add"), (1, "`add` needs a stack depth of 2, found 1".to_owned()));
+        assert_eq!(rejection("push 1 pick 1"), (1, "`pick 1` needs a stack depth of 2, found 1".to_owned()));
+        assert_eq!(
+            check("push 1 push 2 push 3", 2).unwrap_err().message,
+            "`push 3` can take the stack depth to 3, past its size of 2"
+        );
+    }
+
+    #[test]
+    fn underflow_through_call() {
+        assert_eq!(
+            rejection("push 3 call f print halt\nf: pop pop ret"),
+            (1, "`call 4` needs a stack depth of 2, found 1".to_owned())
+        );
+        // a subroutine's own pushes count against the caller's stack
+        assert_eq!(
+            check("call f halt\nf: push 1 push 2 push 3 pop pop pop ret", 2).unwrap_err(),
+            Rejection {
+                ip: 4,
+                message: "`push 3` can take the stack depth to 3, past its size of 2".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn subroutine_depths_are_relative() {
+        let depths = check("push 7 call sq print halt\nsq: dup mul ret", 16).unwrap();
+        assert_eq!(depths[0..4], [0, 1, 1, 0].map(|d| Some(Depth::Absolute(d))));
+        assert_eq!(depths[4..], [0, 1, 0].map(|depth| Some(Depth::Relative { routine: 4, depth })));
+    }
+
+    #[test]
+    fn unbalanced_returns() {
+        assert_eq!(
+            rejection("push 1 call f halt\nf: dup zjump x push 1 ret x: ret"),
+            (6, "returns with the stack changed by +1, but by +0 on another path".to_owned())
+        );
+    }
+
+    #[test]
+    fn recursion() {
+        // factorial: depth depends on the input, so its growth is left to the VM
+        let source = "push 5 call f print halt\nf: dup zjump x dup push 1 sub call f mul ret x: pop push 1 ret";
+        assert!(check(source, 16).is_ok());
+        // but a recursive subroutine must still return consistently
+        assert!(check("push 1 call f halt\nf: dup zjump x push 0 call f x: ret", 16).is_err());
+        // and mutual recursion settles too
+        let source = "push 3 call f halt\nf: dup zjump x push 1 sub call g x: ret\ng: push 0 pop call f ret";
+        assert!(check(source, 16).is_ok());
+    }
+
+    #[test]
+    fn syscall_effects() {
+        let program = assemble("push 1 push 2 syscall 7 print").unwrap();
+        let effect = |n| (n == 7).then_some((2, 1));
+        assert!(verify(&program.code, 0, 16, effect).is_ok());
+        assert_eq!(
+            verify(&program.code, 0, 16, |_| None).unwrap_err().message,
+            "syscall 7 is not registered"
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion_settles_regardless_of_stack_size() {
+        // every level leaves `f` needing one more value from its caller
+        let source = "push 1 call f halt\nf: dup zjump x pop call f push 0 x: ret";
+        assert!(check(source, 1_000_000_000).is_ok());
+    }
+
+    #[test]
+    fn long_call_chains() {
+        // each level keeps one value on the stack across the call to the next
+        let levels = 3000;
+        let mut source = "call f0 halt\n".to_owned();
+        for i in 0..levels {
+            source += &format!("f{i}: push {i} call f{} pop ret\n", i + 1);
+        }
+        source += &format!("f{levels}: ret");
+
+        let depths = check(&source, levels).unwrap();
+        assert_eq!(depths[..2], [Some(Depth::Absolute(0)), Some(Depth::Absolute(0))]);
+        assert_eq!(depths[2..6], [0, 1, 1, 0].map(|depth| Some(Depth::Relative { routine: 2, depth })));
+        let last = 2 + 4 * levels;
+        assert_eq!(depths[last..], [Some(Depth::Relative { routine: last, depth: 0 })]);
+    }
+}
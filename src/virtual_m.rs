@@ -1,16 +1,241 @@
-use crate::instructions::Inst_Set;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+};
+
+use crate::{error::Error, instructions::Inst_Set, program::Program, verify::Depth};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    StackOverflow,
+    StackUnderflow,
+    DivByZero,
+    BadJump(i32),
+    BadStackIndex(i32),
+    IntegerOverflow,
+    ReturnStackOverflow,
+    ReturnStackUnderflow,
+    BadAddress(i32),
+    OutputFailed,
+    InputFailed,
+    BadInput,
+    BadSyscall(i32),
+    SyscallFailed(i32),
+    FuelExhausted,
+}
+
+/// A fault raised by an instruction. The VM is left as it was right before
+/// `instruction` ran, with `ip` still pointing at it.
+#[derive(Debug, Clone, Copy)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub ip: usize,
+    pub instruction: Inst_Set,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapKind::StackOverflow => write!(f, "stack overflow"),
+            TrapKind::StackUnderflow => write!(f, "stack underflow"),
+            TrapKind::DivByZero => write!(f, "division by zero"),
+            TrapKind::BadJump(target) => write!(f, "jump to {target} out of bounds"),
+            TrapKind::BadStackIndex(index) => write!(f, "stack index {index} out of range"),
+            TrapKind::IntegerOverflow => write!(f, "integer overflow"),
+            TrapKind::ReturnStackOverflow => write!(f, "return stack overflow (runaway recursion?)"),
+            TrapKind::ReturnStackUnderflow => write!(f, "`ret` with no call to return from"),
+            TrapKind::BadAddress(address) => write!(f, "memory address {address} out of bounds"),
+            TrapKind::OutputFailed => write!(f, "could not write to the output"),
+            TrapKind::InputFailed => write!(f, "could not read from the input"),
+            TrapKind::BadInput => write!(f, "`readint` found no valid i32 in the input"),
+            TrapKind::BadSyscall(number) => write!(f, "no syscall {number} is registered"),
+            TrapKind::SyscallFailed(number) => write!(f, "syscall {number} failed"),
+            TrapKind::FuelExhausted => write!(f, "instruction limit reached"),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at ip={} (`{}`)", self.kind, self.ip, self.instruction)
+    }
+}
+
+/// What `add`, `sub`, `mul` and `div` do when the result does not fit in an `i32`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArithMode {
+    /// Wrap around in two's complement.
+    #[default]
+    Wrapping,
+    /// Trap with `IntegerOverflow`.
+    Checked,
+    /// Clamp to `i32::MIN` or `i32::MAX`.
+    Saturating,
+}
+
+// an operator in its checked, wrapping and saturating forms
+type Op<T> = (fn(T, T) -> Option<T>, fn(T, T) -> T, fn(T, T) -> T);
+
+const ADD: Op<i32> = (i32::checked_add, i32::wrapping_add, i32::saturating_add);
+const SUB: Op<i32> = (i32::checked_sub, i32::wrapping_sub, i32::saturating_sub);
+const MUL: Op<i32> = (i32::checked_mul, i32::wrapping_mul, i32::saturating_mul);
+const DIV: Op<i32> = (i32::checked_div, i32::wrapping_div, i32::saturating_div);
+const ADD64: Op<i64> = (i64::checked_add, i64::wrapping_add, i64::saturating_add);
+const SUB64: Op<i64> = (i64::checked_sub, i64::wrapping_sub, i64::saturating_sub);
+const MUL64: Op<i64> = (i64::checked_mul, i64::wrapping_mul, i64::saturating_mul);
+const DIV64: Op<i64> = (i64::checked_div, i64::wrapping_div, i64::saturating_div);
+
+// `b OP a`, the same on every build profile
+fn arith<T>(mode: ArithMode, b: T, a: T, (checked, wrapping, saturating): Op<T>) -> Result<T, TrapKind> {
+    match mode {
+        ArithMode::Wrapping => Ok(wrapping(b, a)),
+        ArithMode::Checked => checked(b, a).ok_or(TrapKind::IntegerOverflow),
+        ArithMode::Saturating => Ok(saturating(b, a)),
+    }
+}
+
+pub const DEFAULT_STACK_SIZE: usize = 1024;
+pub const DEFAULT_MEMORY_SIZE: usize = 64 * 1024;
+pub const DEFAULT_CALL_DEPTH: usize = 256;
+
+type Handler = Box<dyn FnMut(&mut Vm) -> Result<(), TrapKind>>;
+
+/// A host function reachable from programs through `syscall N`.
+///
+/// The closure pops its `pops` arguments and pushes its `pushes` results
+/// through the [`Vm`] it is handed; returning a different stack height is
+/// reported as [`TrapKind::SyscallFailed`].
+pub struct Syscall {
+    pub pops: usize,
+    pub pushes: usize,
+    handler: Handler,
+}
+
 pub struct Vm {
-    stack: [i32; 1024],
+    stack: Vec<i32>,
     sp: usize,
+    // return addresses pushed by `call`, separate from the value stack
+    return_stack: Vec<usize>,
+    // byte-addressable, the program's data section sits at address 0
+    memory: Vec<u8>,
+    // configured size, grown per program to fit its data and bss
+    memory_size: usize,
+    // where `print`, `puti`, `putc` and `puts` write
+    output: Box<dyn Write>,
+    // where `getc` and `readint` read from
+    input: Box<dyn BufRead>,
+    syscalls: HashMap<i32, Syscall>,
+    call_depth: usize,
+    arith_mode: ArithMode,
+    // instructions left to run, `None` for no limit
+    fuel: Option<u64>,
     ip: usize,
     instructions: Vec<Inst_Set>,
 }
 
 impl Default for Vm {
     fn default() -> Self {
+        Vm::builder().build()
+    }
+}
+
+/// Configures a [`Vm`] before it is created.
+pub struct VmBuilder {
+    stack_size: usize,
+    call_depth: usize,
+    memory_size: usize,
+    fuel: Option<u64>,
+    arith_mode: ArithMode,
+    output: Box<dyn Write>,
+    input: Box<dyn BufRead>,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            call_depth: DEFAULT_CALL_DEPTH,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            fuel: None,
+            arith_mode: ArithMode::default(),
+            output: Box::new(io::stdout()),
+            input: Box::new(BufReader::new(io::stdin())),
+        }
+    }
+}
+
+impl fmt::Debug for VmBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmBuilder")
+            .field("stack_size", &self.stack_size)
+            .field("call_depth", &self.call_depth)
+            .field("memory_size", &self.memory_size)
+            .field("fuel", &self.fuel)
+            .field("arith_mode", &self.arith_mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VmBuilder {
+    /// Maximum number of values on the stack; pushing past it traps with `StackOverflow`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Nesting depth of `call` before the VM traps with `ReturnStackOverflow`.
+    pub fn call_depth(mut self, depth: usize) -> Self {
+        self.call_depth = depth;
+        self
+    }
+
+    /// Bytes of memory for `load`/`store`. This is a hard limit: `Vm::load`
+    /// refuses a program whose data and bss do not fit in it.
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = size;
+        self
+    }
+
+    /// Number of instructions the VM may execute before it traps with
+    /// `FuelExhausted`, so even a program that loops forever terminates.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Overflow behaviour of `add`, `sub`, `mul` and `div`; `addc`, `subc`
+    /// and `mulc` always trap.
+    pub fn arith_mode(mut self, mode: ArithMode) -> Self {
+        self.arith_mode = mode;
+        self
+    }
+
+    /// Where the program's output goes, stdout unless set.
+    pub fn output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Where the program's input comes from, stdin unless set.
+    pub fn input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Box::new(input);
+        self
+    }
+
+    pub fn build(self) -> Vm {
         Vm {
-            stack: [0; 1024],
+            stack: vec![0; self.stack_size],
             sp: 0,
+            return_stack: vec![],
+            memory: vec![0; self.memory_size],
+            memory_size: self.memory_size,
+            output: self.output,
+            input: self.input,
+            syscalls: HashMap::new(),
+            call_depth: self.call_depth,
+            arith_mode: self.arith_mode,
+            fuel: self.fuel,
             instructions: vec![],
             ip: 0,
         }
@@ -18,190 +243,931 @@ impl Default for Vm {
 }
 
 impl Vm {
-    pub fn copy_ins(&mut self, ins: &[Inst_Set]) {
-        self.instructions = ins.to_vec();
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    /// Makes `handler` callable as `syscall number`, replacing any earlier
+    /// registration. `pops` and `pushes` declare its effect on the stack, which
+    /// the VM checks before and after every call.
+    ///
+    /// ```
+    /// use virtual_tsoding::{assemble, Vm};
+    ///
+    /// let mut vm = Vm::default();
+    /// // syscall 1: ( a b -- max )
+    /// vm.register_syscall(1, 2, 1, |vm| {
+    ///     let a = vm.pop()?;
+    ///     let b = vm.pop()?;
+    ///     vm.push(a.max(b))
+    /// });
+    /// vm.load(&assemble("push 3 push 9 syscall 1").unwrap()).unwrap();
+    /// vm.start().unwrap();
+    /// assert_eq!(vm.stack(), [9]);
+    /// ```
+    pub fn register_syscall(
+        &mut self,
+        number: i32,
+        pops: usize,
+        pushes: usize,
+        handler: impl FnMut(&mut Vm) -> Result<(), TrapKind> + 'static,
+    ) {
+        let handler = Box::new(handler);
+        self.syscalls.insert(number, Syscall { pops, pushes, handler });
+    }
+
+    pub fn syscall(&self, number: i32) -> Option<&Syscall> {
+        self.syscalls.get(&number)
     }
-    pub fn dup(&mut self, index: usize) {
-        if index >= self.sp {
-            eprint!("ERROR: Index out of range");
+
+    /// Loads bare code starting at index 0, verified like [`Vm::load`].
+    pub fn copy_ins(&mut self, ins: &[Inst_Set]) -> Result<(), Error> {
+        self.load(&Program::new(ins.to_vec()))
+    }
+
+    /// Verifies `program` against this VM's stack size, memory size and
+    /// registered syscalls, then loads it onto an empty stack and return stack
+    /// and freshly zeroed memory. A program that is refused leaves the VM as it was.
+    pub fn load(&mut self, program: &Program) -> Result<(), Error> {
+        let needed = program.data.len() as u64 + program.bss as u64;
+        if needed > self.memory_size as u64 {
+            return Err(Error::Load {
+                message: format!(
+                    "the program needs {needed} bytes of data and bss, but memory holds {}",
+                    self.memory_size
+                ),
+            });
         }
+        self.verify(program)?;
+        self.instructions = program.code.clone();
+        self.ip = program.entry as usize;
+        self.sp = 0;
+        self.return_stack.clear();
+        self.memory.clear();
+        self.memory.resize(self.memory_size, 0);
+        self.memory[..program.data.len()].copy_from_slice(&program.data);
+        Ok(())
+    }
+
+    /// The stack depth before each instruction of `program`, or why it cannot run here.
+    pub fn verify(&self, program: &Program) -> Result<Vec<Option<Depth>>, Error> {
+        program.verify(self.stack.len(), |number| self.syscall(number).map(|s| (s.pops, s.pushes)))
+    }
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+    /// The live part of the stack, bottom first.
+    pub fn stack(&self) -> &[i32] {
+        &self.stack[..self.sp]
+    }
+    /// Addresses `ret` will jump back to, innermost call last.
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+    pub fn instructions(&self) -> &[Inst_Set] {
+        &self.instructions
+    }
+    /// Instructions left before `FuelExhausted`, if a limit was set.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    pub fn is_halted(&self) -> bool {
+        self.ip >= self.instructions.len()
+    }
+
+    pub fn dup(&mut self, index: i32) -> Result<(), TrapKind> {
+        let index = self.stack_index(index)?;
         let elem = self.stack[index];
-        self.push(elem);
+        self.push(elem)
     }
-    pub fn swap(&mut self, index: usize) {
-        if index >= self.sp {
-            eprint!("ERROR: Index out of range");
-        }
+    pub fn swap(&mut self, index: i32) -> Result<(), TrapKind> {
+        let index = self.stack_index(index)?;
         let last_index = self.sp - 1;
         self.stack.swap(index, last_index);
+        Ok(())
+    }
+    /// Pops the top value into absolute slot `index` of what is left.
+    pub fn set(&mut self, index: i32) -> Result<(), TrapKind> {
+        let elem = self.pop()?;
+        let index = self.stack_index(index)?;
+        self.stack[index] = elem;
+        Ok(())
+    }
+    /// Pushes a copy of the value `depth` below the top; `pick 0` is `dup`.
+    pub fn pick(&mut self, depth: i32) -> Result<(), TrapKind> {
+        let index = self.relative_index(depth)?;
+        let elem = self.stack[index];
+        self.push(elem)
+    }
+    /// Moves the value `depth` below the top onto the top; `roll 1` is `swap`.
+    pub fn roll(&mut self, depth: i32) -> Result<(), TrapKind> {
+        let index = self.relative_index(depth)?;
+        self.stack[index..self.sp].rotate_left(1);
+        Ok(())
     }
 
-    pub fn start(&mut self) {
-        while self.ip < self.instructions.len() {
-            let i = &self.instructions[self.ip];
+    // slots from the bottom of the stack
+    fn stack_index(&self, index: i32) -> Result<usize, TrapKind> {
+        match usize::try_from(index) {
+            Ok(i) if i < self.sp => Ok(i),
+            _ => Err(TrapKind::BadStackIndex(index)),
+        }
+    }
 
-            match i {
-                Inst_Set::INST_INDUP { value } => {
-                    self.dup(*value as usize);
-                }
-                Inst_Set::INST_ISWAP { value } => {
-                    self.swap(*value as usize);
-                }
-                Inst_Set::INST_NOP { _pad } => {
-                    // nothing
-                }
-                Inst_Set::INST_HALT { _pad } => {
-                    self.ip = self.instructions.len();
-                }
-                Inst_Set::INST_MOD { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b % a);
-                }
-                Inst_Set::INST_CMPGE { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a >= b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
-                }
-                Inst_Set::INST_CMPLE { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a <= b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
-                }
-                Inst_Set::INST_CMPG { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a > b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
+    // slots down from the top of the stack, 0 being the top
+    fn relative_index(&self, depth: i32) -> Result<usize, TrapKind> {
+        match usize::try_from(depth) {
+            Ok(d) if d < self.sp => Ok(self.sp - 1 - d),
+            _ => Err(TrapKind::BadStackIndex(depth)),
+        }
+    }
+
+    // the `width` bytes starting at `address`, if they all lie inside memory
+    fn address(&self, address: i32, width: usize) -> Result<usize, TrapKind> {
+        match usize::try_from(address) {
+            Ok(start) if start + width <= self.memory.len() => Ok(start),
+            _ => Err(TrapKind::BadAddress(address)),
+        }
+    }
+
+    // landing one past the last instruction is allowed and simply ends the program
+    fn jump_target(&self, value: i32) -> Result<usize, TrapKind> {
+        match usize::try_from(value) {
+            Ok(ins) if ins <= self.instructions.len() => Ok(ins),
+            _ => Err(TrapKind::BadJump(value)),
+        }
+    }
+
+    pub fn start(&mut self) -> Result<(), Trap> {
+        while !self.is_halted() {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Executes the instruction at `ip`, doing nothing once the program has
+    /// halted. On a trap the stack is rolled back, so the VM looks exactly as
+    /// it did before the instruction, except for any memory a failing syscall
+    /// handler wrote.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        let Some(&instruction) = self.instructions.get(self.ip) else {
+            return Ok(());
+        };
+        let sp = self.sp;
+        let result = match self.fuel {
+            Some(0) => Err(TrapKind::FuelExhausted),
+            _ => self.execute(instruction),
+        };
+        match result {
+            Ok(next) => {
+                self.ip = next;
+                if let Some(fuel) = &mut self.fuel {
+                    *fuel -= 1;
                 }
-                Inst_Set::INST_CMPL { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a < b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
+                Ok(())
+            }
+            Err(kind) => {
+                self.sp = sp;
+                Err(Trap {
+                    kind,
+                    ip: self.ip,
+                    instruction,
+                })
+            }
+        }
+    }
+
+    // returns the index of the next instruction to run
+    fn execute(&mut self, i: Inst_Set) -> Result<usize, TrapKind> {
+        let mut next = self.ip + 1;
+        match i {
+            Inst_Set::INST_INDUP { value } => {
+                self.dup(value)?;
+            }
+            Inst_Set::INST_ISWAP { value } => {
+                self.swap(value)?;
+            }
+            Inst_Set::INST_GETABS { value } => {
+                self.dup(value)?;
+            }
+            Inst_Set::INST_SETABS { value } => {
+                self.set(value)?;
+            }
+            Inst_Set::INST_PICK { value } => {
+                self.pick(value)?;
+            }
+            Inst_Set::INST_ROLL { value } => {
+                self.roll(value)?;
+            }
+            Inst_Set::INST_OVER { _pad } => {
+                self.pick(1)?;
+            }
+            Inst_Set::INST_ROT { _pad } => {
+                self.roll(2)?;
+            }
+            Inst_Set::INST_NOP { _pad } => {
+                // nothing
+            }
+            Inst_Set::INST_HALT { _pad } => {
+                next = self.instructions.len();
+            }
+            Inst_Set::INST_MOD { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                if a == 0 {
+                    return Err(TrapKind::DivByZero);
                 }
-                Inst_Set::INST_NZJMP { value } => {
-                    let ins = *value as usize;
-                    assert!(ins < self.instructions.len(), "jump out of bound");
-                    let a = self.pop();
-                    if a != 0 {
-                        self.ip = ins - 1;
-                    }
+                // the only overflowing case, `i32::MIN % -1`, is 0 in every mode
+                self.push(b.wrapping_rem(a))?;
+            }
+            Inst_Set::INST_CMPGE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a >= b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_ZJMP { value } => {
-                    let ins = *value as usize;
-                    assert!(ins < self.instructions.len(), "jump out of bound");
-                    let a = self.pop();
-                    if a == 0 {
-                        self.ip = ins - 1;
-                    }
+            }
+            Inst_Set::INST_CMPLE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a <= b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_JP { value } => {
-                    let ins = *value as usize;
-                    assert!(ins < self.instructions.len(), "jump out of bound");
-                    self.ip = ins - 1;
+            }
+            Inst_Set::INST_CMPG { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a > b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_CMPNE { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a != b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
+            }
+            Inst_Set::INST_CMPL { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a < b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_CMPE { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b);
-                    self.push(a);
-                    if a == b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
+            }
+            Inst_Set::INST_NZJMP { value } => {
+                let ins = self.jump_target(value)?;
+                let a = self.pop()?;
+                if a != 0 {
+                    next = ins;
                 }
-                Inst_Set::INST_SWAP { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(a);
-                    self.push(b);
+            }
+            Inst_Set::INST_ZJMP { value } => {
+                let ins = self.jump_target(value)?;
+                let a = self.pop()?;
+                if a == 0 {
+                    next = ins;
                 }
-                Inst_Set::INST_DUP { _pad } => {
-                    let a = self.pop();
-                    self.push(a);
-                    self.push(a);
+            }
+            Inst_Set::INST_JP { value } => {
+                let ins = self.jump_target(value)?;
+                next = ins;
+            }
+            Inst_Set::INST_CMPNE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a != b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_PUSH { value } => {
-                    self.push(*value);
+            }
+            Inst_Set::INST_CMPE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+                if a == b {
+                    self.push(1)?;
+                } else {
+                    self.push(0)?;
                 }
-                Inst_Set::INST_POP { _pad } => {
-                    self.pop();
+            }
+            Inst_Set::INST_SWAP { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a)?;
+                self.push(b)?;
+            }
+            Inst_Set::INST_DUP { _pad } => {
+                let a = self.pop()?;
+                self.push(a)?;
+                self.push(a)?;
+            }
+            Inst_Set::INST_PUSH { value } => {
+                self.push(value)?;
+            }
+            Inst_Set::INST_POP { _pad } => {
+                self.pop()?;
+            }
+            Inst_Set::INST_ADD { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(self.arith_mode, b, a, ADD)?)?;
+            }
+            Inst_Set::INST_ADDC { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(ArithMode::Checked, b, a, ADD)?)?;
+            }
+            Inst_Set::INST_SUB { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(self.arith_mode, b, a, SUB)?)?;
+            }
+            Inst_Set::INST_SUBC { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(ArithMode::Checked, b, a, SUB)?)?;
+            }
+            Inst_Set::INST_MUL { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(self.arith_mode, b, a, MUL)?)?;
+            }
+            Inst_Set::INST_MULC { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(arith(ArithMode::Checked, b, a, MUL)?)?;
+            }
+            Inst_Set::INST_DIV { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                if  a == 0 {
+                    return Err(TrapKind::DivByZero);
                 }
-                Inst_Set::INST_ADD { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    //println!("debug :- {a},{b}");
-                    self.push(a + b);
+                self.push(arith(self.arith_mode, b, a, DIV)?)?;
+            }
+            // unlike the `cmp*` family these consume both operands and compare `b OP a`
+            Inst_Set::INST_EQ { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b == a) as i32)?;
+            }
+            Inst_Set::INST_NE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b != a) as i32)?;
+            }
+            Inst_Set::INST_LT { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b < a) as i32)?;
+            }
+            Inst_Set::INST_GT { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b > a) as i32)?;
+            }
+            Inst_Set::INST_LE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b <= a) as i32)?;
+            }
+            Inst_Set::INST_GE { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b >= a) as i32)?;
+            }
+            Inst_Set::INST_AND { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b & a)?;
+            }
+            Inst_Set::INST_OR { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b | a)?;
+            }
+            Inst_Set::INST_XOR { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b ^ a)?;
+            }
+            Inst_Set::INST_NOT { _pad } => {
+                let a = self.pop()?;
+                self.push(!a)?;
+            }
+            // shifts move `b` by the low 5 bits of `a`
+            Inst_Set::INST_SHL { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b.wrapping_shl(a as u32))?;
+            }
+            Inst_Set::INST_SHR { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b.wrapping_shr(a as u32))?;
+            }
+            Inst_Set::INST_USHR { _pad } => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((b as u32).wrapping_shr(a as u32) as i32)?;
+            }
+            // 64-bit values take two slots, low word first and high word on top
+            Inst_Set::INST_LADD { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                self.push_long(arith(self.arith_mode, b, a, ADD64)?)?;
+            }
+            Inst_Set::INST_LSUB { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                self.push_long(arith(self.arith_mode, b, a, SUB64)?)?;
+            }
+            Inst_Set::INST_LMUL { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                self.push_long(arith(self.arith_mode, b, a, MUL64)?)?;
+            }
+            Inst_Set::INST_LDIV { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                if a == 0 {
+                    return Err(TrapKind::DivByZero);
                 }
-                Inst_Set::INST_SUB { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(b-a);
+                self.push_long(arith(self.arith_mode, b, a, DIV64)?)?;
+            }
+            Inst_Set::INST_LMOD { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                if a == 0 {
+                    return Err(TrapKind::DivByZero);
                 }
-                Inst_Set::INST_MUL { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(a * b);
+                self.push_long(b.wrapping_rem(a))?;
+            }
+            Inst_Set::INST_LPRINT { _pad } => {
+                let a = self.pop_long()?;
+                self.write(format!("{a}\n").as_bytes())?;
+            }
+            Inst_Set::INST_I2L { _pad } => {
+                let a = self.pop()?;
+                self.push_long(a.into())?;
+            }
+            Inst_Set::INST_L2I { _pad } => {
+                let a = self.pop_long()?;
+                self.push(a as i32)?;
+            }
+            Inst_Set::INST_LCMP { _pad } => {
+                let a = self.pop_long()?;
+                let b = self.pop_long()?;
+                self.push(b.cmp(&a) as i32)?;
+            }
+            Inst_Set::INST_FADD { _pad } => {
+                let a = self.pop_f64()?;
+                let b = self.pop_f64()?;
+                self.push_f64(b + a)?;
+            }
+            Inst_Set::INST_FSUB { _pad } => {
+                let a = self.pop_f64()?;
+                let b = self.pop_f64()?;
+                self.push_f64(b - a)?;
+            }
+            Inst_Set::INST_FMUL { _pad } => {
+                let a = self.pop_f64()?;
+                let b = self.pop_f64()?;
+                self.push_f64(b * a)?;
+            }
+            Inst_Set::INST_FDIV { _pad } => {
+                let a = self.pop_f64()?;
+                let b = self.pop_f64()?;
+                self.push_f64(b / a)?;
+            }
+            Inst_Set::INST_FPRINT { _pad } => {
+                let a = self.pop_f64()?;
+                self.write(format!("{a}\n").as_bytes())?;
+            }
+            Inst_Set::INST_I2F { _pad } => {
+                let a = self.pop()?;
+                self.push_f64(a.into())?;
+            }
+            Inst_Set::INST_L2F { _pad } => {
+                let a = self.pop_long()?;
+                self.push_f64(a as f64)?;
+            }
+            // float to integer saturates at the type's bounds, NaN becomes 0
+            Inst_Set::INST_F2I { _pad } => {
+                let a = self.pop_f64()?;
+                self.push(a as i32)?;
+            }
+            Inst_Set::INST_F2L { _pad } => {
+                let a = self.pop_f64()?;
+                self.push_long(a as i64)?;
+            }
+            // -1, 0 or 1 for less, equal or greater; NaN compares as less
+            Inst_Set::INST_FCMP { _pad } => {
+                let a = self.pop_f64()?;
+                let b = self.pop_f64()?;
+                self.push(b.partial_cmp(&a).map_or(-1, |order| order as i32))?;
+            }
+            Inst_Set::INST_CALL { value } => {
+                let ins = self.jump_target(value)?;
+                if self.return_stack.len() >= self.call_depth {
+                    return Err(TrapKind::ReturnStackOverflow);
                 }
-                Inst_Set::INST_DIV { _pad } => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    if  a == 0 {
-                        panic!("cannot div by zero");
+                self.return_stack.push(next);
+                next = ins;
+            }
+            Inst_Set::INST_RET { _pad } => {
+                next = self.return_stack.pop().ok_or(TrapKind::ReturnStackUnderflow)?;
+            }
+            Inst_Set::INST_LOAD8 { _pad } => {
+                let address = self.pop()?;
+                let at = self.address(address, 1)?;
+                self.push(self.memory[at] as i32)?;
+            }
+            Inst_Set::INST_LOAD32 { _pad } => {
+                let address = self.pop()?;
+                let at = self.address(address, 4)?;
+                self.push(i32::from_le_bytes(self.memory[at..at + 4].try_into().unwrap()))?;
+            }
+            // stores take the address on top and the value under it
+            Inst_Set::INST_STORE8 { _pad } => {
+                let address = self.pop()?;
+                let value = self.pop()?;
+                let at = self.address(address, 1)?;
+                self.memory[at] = value as u8;
+            }
+            Inst_Set::INST_STORE32 { _pad } => {
+                let address = self.pop()?;
+                let value = self.pop()?;
+                let at = self.address(address, 4)?;
+                self.memory[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            Inst_Set::INST_GETC { _pad } => {
+                self.room_for(1)?;
+                let a = match self.peek_input()? {
+                    Some(a) => {
+                        self.input.consume(1);
+                        a as i32
+                    }
+                    None => -1,
+                };
+                self.push(a)?;
+            }
+            // pushes the number and 1, or 0 and 0 once the input is exhausted
+            Inst_Set::INST_READINT { _pad } => {
+                self.room_for(2)?;
+                match self.read_int()? {
+                    Some(a) => {
+                        self.push(a)?;
+                        self.push(1)?;
+                    }
+                    None => {
+                        self.push(0)?;
+                        self.push(0)?;
                     }
-                    self.push(b/a);
-                }
-                Inst_Set::INST_PRINT { _pad } => {
-                    println!("{}", self.pop());
                 }
             }
-            self.ip += 1;
+            Inst_Set::INST_SYSCALL { value } => {
+                self.syscall_call(value)?;
+            }
+            Inst_Set::INST_PRINT { _pad } => {
+                let a = self.pop()?;
+                self.write(format!("{a}\n").as_bytes())?;
+            }
+            Inst_Set::INST_PUTI { _pad } => {
+                let a = self.pop()?;
+                self.write(a.to_string().as_bytes())?;
+            }
+            Inst_Set::INST_PUTC { _pad } => {
+                let a = self.pop()?;
+                self.write(&[a as u8])?;
+            }
+            // writes the NUL-terminated string at the popped address
+            Inst_Set::INST_PUTS { _pad } => {
+                let address = self.pop()?;
+                let start = self.address(address, 1)?;
+                let Some(len) = self.memory[start..].iter().position(|b| *b == 0) else {
+                    return Err(TrapKind::BadAddress(self.memory.len() as i32));
+                };
+                let end = start + len;
+                self.output
+                    .write_all(&self.memory[start..end])
+                    .map_err(|_| TrapKind::OutputFailed)?;
+            }
+        }
+        Ok(next)
+    }
+
+    // the handler is taken out of the table while it runs so it can borrow the whole VM
+    fn syscall_call(&mut self, number: i32) -> Result<(), TrapKind> {
+        let mut syscall = self.syscalls.remove(&number).ok_or(TrapKind::BadSyscall(number))?;
+        let result = self.run_syscall(number, &mut syscall);
+        self.syscalls.entry(number).or_insert(syscall);
+        result
+    }
+
+    fn run_syscall(&mut self, number: i32, syscall: &mut Syscall) -> Result<(), TrapKind> {
+        if self.sp < syscall.pops {
+            return Err(TrapKind::StackUnderflow);
+        }
+        let sp = self.sp - syscall.pops + syscall.pushes;
+        if sp > self.stack.len() {
+            return Err(TrapKind::StackOverflow);
+        }
+        // a failing handler may have popped and overwritten slots before giving up
+        let saved = self.stack[..self.sp].to_vec();
+        let mut result = (syscall.handler)(self);
+        if result.is_ok() && self.sp != sp {
+            result = Err(TrapKind::SyscallFailed(number));
+        }
+        if result.is_err() {
+            self.stack[..saved.len()].copy_from_slice(&saved);
+        }
+        result
+    }
+
+    fn peek_input(&mut self) -> Result<Option<u8>, TrapKind> {
+        let buf = self.input.fill_buf().map_err(|_| TrapKind::InputFailed)?;
+        Ok(buf.first().copied())
+    }
+
+    // skips leading whitespace, then reads an optional sign and decimal digits,
+    // leaving whatever follows them in the input
+    fn read_int(&mut self) -> Result<Option<i32>, TrapKind> {
+        while self.peek_input()?.is_some_and(|a| a.is_ascii_whitespace()) {
+            self.input.consume(1);
+        }
+        let mut text = String::new();
+        while let Some(a) = self.peek_input()? {
+            let sign = text.is_empty() && (a == b'-' || a == b'+');
+            if !sign && !a.is_ascii_digit() {
+                break;
+            }
+            text.push(a as char);
+            self.input.consume(1);
+        }
+        if text.is_empty() && self.peek_input()?.is_none() {
+            return Ok(None);
+        }
+        text.parse().map(Some).map_err(|_| TrapKind::BadInput)
+    }
+
+    // input is consumed for good, so check the stack before reading any
+    fn room_for(&self, n: usize) -> Result<(), TrapKind> {
+        if self.sp + n > self.stack.len() {
+            return Err(TrapKind::StackOverflow);
         }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), TrapKind> {
+        self.output.write_all(bytes).map_err(|_| TrapKind::OutputFailed)
     }
 
-    pub fn push(&mut self, value: i32) {
-        assert!(self.sp < 1024, "Stack overflow");
+    pub fn push(&mut self, value: i32) -> Result<(), TrapKind> {
+        if self.sp >= self.stack.len() {
+            return Err(TrapKind::StackOverflow);
+        }
         self.stack[self.sp] = value;
         self.sp += 1;
+        Ok(())
+    }
+    pub fn push_long(&mut self, value: i64) -> Result<(), TrapKind> {
+        self.room_for(2)?;
+        self.push(value as i32)?;
+        self.push((value >> 32) as i32)
+    }
+    pub fn pop_long(&mut self) -> Result<i64, TrapKind> {
+        if self.sp < 2 {
+            return Err(TrapKind::StackUnderflow);
+        }
+        let high = self.pop()?;
+        let low = self.pop()?;
+        Ok((i64::from(high) << 32) | i64::from(low as u32))
     }
-    pub fn pop(&mut self) -> i32 {
-        assert!(self.sp > 0, "stack underflow");
+    pub fn push_f64(&mut self, value: f64) -> Result<(), TrapKind> {
+        self.push_long(value.to_bits() as i64)
+    }
+    pub fn pop_f64(&mut self) -> Result<f64, TrapKind> {
+        Ok(f64::from_bits(self.pop_long()? as u64))
+    }
+    pub fn pop(&mut self) -> Result<i32, TrapKind> {
+        if self.sp == 0 {
+            return Err(TrapKind::StackUnderflow);
+        }
         self.sp -= 1;
-        self.stack[self.sp]
+        Ok(self.stack[self.sp])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn step_after_halt_does_nothing() {
+        let mut vm = Vm::builder().output(std::io::sink()).build();
+        vm.load(&assemble("push 1 print").unwrap()).unwrap();
+        vm.start().unwrap();
+        assert!(vm.is_halted());
+        vm.step().unwrap();
+        assert_eq!(vm.ip(), 2);
+        assert_eq!(vm.sp(), 0);
+    }
+
+    // runs `source` to the end and returns the stack it leaves
+    fn run(mode: ArithMode, source: &str) -> Result<Vec<i32>, TrapKind> {
+        let mut vm = Vm::builder().arith_mode(mode).output(std::io::sink()).build();
+        vm.load(&assemble(source).unwrap()).unwrap();
+        vm.start().map_err(|trap| trap.kind)?;
+        Ok(vm.stack().to_vec())
+    }
+
+    #[test]
+    fn arith_modes() {
+        let overflow = Err(TrapKind::IntegerOverflow);
+        // `b op a`, then what Wrapping, Checked and Saturating make of it
+        let cases = [
+            ("add", 2, 3, Ok(5), Ok(5), Ok(5)),
+            ("add", i32::MAX, 1, Ok(i32::MIN), overflow, Ok(i32::MAX)),
+            ("add", i32::MIN, -1, Ok(i32::MAX), overflow, Ok(i32::MIN)),
+            ("sub", 2, 3, Ok(-1), Ok(-1), Ok(-1)),
+            ("sub", i32::MIN, 1, Ok(i32::MAX), overflow, Ok(i32::MIN)),
+            ("sub", i32::MAX, -1, Ok(i32::MIN), overflow, Ok(i32::MAX)),
+            ("mul", -4, 3, Ok(-12), Ok(-12), Ok(-12)),
+            ("mul", i32::MAX, 2, Ok(-2), overflow, Ok(i32::MAX)),
+            ("mul", i32::MIN, 2, Ok(0), overflow, Ok(i32::MIN)),
+            ("div", -7, 2, Ok(-3), Ok(-3), Ok(-3)),
+            ("div", i32::MIN, -1, Ok(i32::MIN), overflow, Ok(i32::MAX)),
+            ("div", 1, 0, Err(TrapKind::DivByZero), Err(TrapKind::DivByZero), Err(TrapKind::DivByZero)),
+        ];
+        for (op, b, a, wrapping, checked, saturating) in cases {
+            let source = format!("push {b} push {a} {op}");
+            for (mode, expected) in [
+                (ArithMode::Wrapping, wrapping),
+                (ArithMode::Checked, checked),
+                (ArithMode::Saturating, saturating),
+            ] {
+                // a trap leaves both operands where they were
+                let expected = expected.map(|result| vec![result]).map_err(|kind| (kind, vec![b, a]));
+                let mut vm = Vm::builder().arith_mode(mode).build();
+                vm.load(&assemble(&source).unwrap()).unwrap();
+                let result = vm.start().map_err(|trap| (trap.kind, vm.stack().to_vec()));
+                assert_eq!(result.map(|()| vm.stack().to_vec()), expected, "{source} in {mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn min_mod_minus_one_is_zero() {
+        // `i32::MIN / -1` is among the `div` cases above
+        for mode in [ArithMode::Wrapping, ArithMode::Checked, ArithMode::Saturating] {
+            assert_eq!(run(mode, "push -2147483648 push -1 mod"), Ok(vec![0]), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn checked_ops_trap_in_every_mode() {
+        for mode in [ArithMode::Wrapping, ArithMode::Checked, ArithMode::Saturating] {
+            assert_eq!(run(mode, "push 2147483647 push 1 addc"), Err(TrapKind::IntegerOverflow), "{mode:?}");
+            assert_eq!(run(mode, "push -2147483648 push 1 subc"), Err(TrapKind::IntegerOverflow), "{mode:?}");
+            assert_eq!(run(mode, "push 65536 push 65536 mulc"), Err(TrapKind::IntegerOverflow), "{mode:?}");
+            assert_eq!(run(mode, "push 2 push 3 addc push 1 subc push 4 mulc"), Ok(vec![16]), "{mode:?}");
+        }
+    }
+
+    // steps `instruction` on a stack holding `stack`, without the verifier that
+    // would refuse it, and returns the trap and the stack afterwards
+    fn step_unverified(stack: &[i32], instruction: Inst_Set) -> (Result<(), TrapKind>, Vec<i32>) {
+        let mut vm = Vm::default();
+        for &value in stack {
+            vm.push(value).unwrap();
+        }
+        vm.instructions = vec![instruction];
+        let result = vm.step().map_err(|trap| trap.kind);
+        (result, vm.stack().to_vec())
+    }
+
+    #[test]
+    fn relative_addressing() {
+        let after = |code: &str| run(ArithMode::default(), &format!("push 1 push 2 push 3 {code}")).unwrap();
+        assert_eq!(after("pick 0"), after("dup"));
+        assert_eq!(after("roll 1"), after("swap"));
+        assert_eq!(after("pick 0"), [1, 2, 3, 3]);
+        assert_eq!(after("pick 2"), [1, 2, 3, 1]);
+        assert_eq!(after("roll 0"), [1, 2, 3]);
+        assert_eq!(after("roll 1"), [1, 3, 2]);
+        assert_eq!(after("roll 2"), [2, 3, 1]);
+        assert_eq!(after("over"), [1, 2, 3, 2]);
+        assert_eq!(after("rot"), [2, 3, 1]);
+    }
+
+    #[test]
+    fn absolute_addressing() {
+        let after = |code: &str| run(ArithMode::default(), &format!("push 1 push 2 push 3 {code}")).unwrap();
+        assert_eq!(after("getabs 0"), [1, 2, 3, 1]);
+        // the value is popped first, so slot 1 is the last one left
+        assert_eq!(after("setabs 0"), [3, 2]);
+        assert_eq!(after("setabs 1"), [1, 3]);
+    }
+
+    #[test]
+    fn bad_stack_indexes_trap_and_leave_the_stack() {
+        let stack = [1, 2, 3];
+        let cases = [
+            (Inst_Set::INST_PICK { value: 3 }, 3),
+            (Inst_Set::INST_PICK { value: -1 }, -1),
+            (Inst_Set::INST_ROLL { value: 3 }, 3),
+            (Inst_Set::INST_GETABS { value: 3 }, 3),
+            // with the value popped, only slots 0 and 1 are left
+            (Inst_Set::INST_SETABS { value: 2 }, 2),
+        ];
+        for (instruction, index) in cases {
+            let (result, after) = step_unverified(&stack, instruction);
+            assert_eq!(result, Err(TrapKind::BadStackIndex(index)), "{instruction}");
+            assert_eq!(after, stack, "{instruction}");
+        }
+        assert_eq!(step_unverified(&[], Inst_Set::INST_PICK { value: 0 }).0, Err(TrapKind::BadStackIndex(0)));
+    }
+
+    #[test]
+    fn load_starts_from_a_clean_vm() {
+        let mut vm = Vm::builder().stack_size(2).memory_size(16).build();
+        vm.load(&assemble(".reserve big 8\npush 1 push 2 call f\nf: halt").unwrap()).unwrap();
+        vm.start().unwrap();
+        vm.memory_mut()[0] = 7;
+        assert_eq!((vm.sp(), vm.return_stack().len()), (2, 1));
+
+        vm.load(&assemble("push 3").unwrap()).unwrap();
+        assert_eq!((vm.sp(), vm.return_stack().len()), (0, 0));
+        assert_eq!(vm.memory(), [0; 16]);
+        vm.start().unwrap();
+        assert_eq!(vm.stack(), [3]);
+    }
+
+    #[test]
+    fn load_refuses_data_past_memory_size() {
+        let mut vm = Vm::builder().memory_size(16).build();
+        let err = vm.load(&assemble(".reserve big 200000000\npush big").unwrap()).unwrap_err();
+        assert!(matches!(err, Error::Load { .. }), "{err:?}");
+        assert_eq!(vm.memory().len(), 16);
+
+        // a bss size read straight from a file is refused without allocating it
+        let mut program = assemble("halt").unwrap();
+        program.bss = u32::MAX;
+        assert!(matches!(vm.load(&program), Err(Error::Load { .. })));
+
+        let program = assemble(".data\nx: .byte 1 2 3 4\n.reserve y 12\n.text\nhalt").unwrap();
+        vm.load(&program).unwrap();
+        assert_eq!(vm.memory().len(), 16);
+    }
+
+    #[test]
+    fn failing_syscall_leaves_stack_untouched() {
+        let mut vm = Vm::builder().build();
+        vm.register_syscall(1, 2, 1, |vm| {
+            vm.pop()?;
+            vm.pop()?;
+            vm.push(99)?;
+            Err(TrapKind::BadInput)
+        });
+        vm.load(&assemble("push 5 push 6 push 7 syscall 1").unwrap()).unwrap();
+        let trap = vm.start().unwrap_err();
+        assert_eq!(trap.kind, TrapKind::BadInput);
+        assert_eq!(vm.stack(), [5, 6, 7]);
+
+        // a handler that leaves the wrong height is undone the same way
+        let mut vm = Vm::builder().build();
+        vm.register_syscall(1, 2, 1, |vm| {
+            vm.pop()?;
+            vm.push(99)
+        });
+        vm.load(&assemble("push 5 push 6 push 7 syscall 1").unwrap()).unwrap();
+        let trap = vm.start().unwrap_err();
+        assert_eq!(trap.kind, TrapKind::SyscallFailed(1));
+        assert_eq!(vm.stack(), [5, 6, 7]);
     }
 }